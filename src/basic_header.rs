@@ -1,8 +1,24 @@
-pub(crate) trait BasicHeader: BasicHeaderClone {
+use std::io;
+
+pub trait BasicHeader: BasicHeaderClone {
     fn get_fmt(&self) -> u8;
     fn get_cs_id(&self) -> u32;
+    fn as_bytes(&self) -> &[u8];
+}
+// Picks the smallest basic header able to carry `cs_id` (2 - 65599, 0 and 1 are
+// reserved to signal the 2 and 3 byte forms).
+pub fn new_basic_header(fmt: u8, cs_id: u32) -> io::Result<Box<dyn BasicHeader>> {
+    match cs_id {
+        2..=63 => Ok(Box::new(BasicHeader1::new_from_values(fmt, cs_id as u8))),
+        64..=319 => Ok(Box::new(BasicHeader2::new_from_values(fmt, cs_id))),
+        320..=65599 => Ok(Box::new(BasicHeader3::new_from_values(fmt, cs_id))),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid chunk stream id {}", cs_id),
+        )),
+    }
 }
-trait BasicHeaderClone {
+pub trait BasicHeaderClone {
     fn clone_box(&self) -> Box<dyn BasicHeader>;
 }
 impl<T> BasicHeaderClone for T
@@ -31,6 +47,9 @@ impl BasicHeader1 {
     }
 }
 impl BasicHeader for BasicHeader1 {
+    fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
     fn get_fmt(&self) -> u8 {
         self.data[0] >> 6 & 0b11
     }
@@ -47,8 +66,17 @@ impl BasicHeader2 {
     pub fn new(data: [u8; 2]) -> Self {
         Self { data }
     }
+    // cs_id 64 - 319
+    pub fn new_from_values(fmt: u8, cs_id: u32) -> Self {
+        Self {
+            data: [fmt << 6, (cs_id - 64) as u8],
+        }
+    }
 }
 impl BasicHeader for BasicHeader2 {
+    fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
     fn get_fmt(&self) -> u8 {
         self.data[0] >> 6 & 0b11
     }
@@ -65,8 +93,18 @@ impl BasicHeader3 {
     pub fn new(data: [u8; 3]) -> Self {
         Self { data }
     }
+    // cs_id 64 - 65599
+    pub fn new_from_values(fmt: u8, cs_id: u32) -> Self {
+        let id = cs_id - 64;
+        Self {
+            data: [fmt << 6 | 1, (id & 0xFF) as u8, (id >> 8) as u8],
+        }
+    }
 }
 impl BasicHeader for BasicHeader3 {
+    fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
     fn get_fmt(&self) -> u8 {
         self.data[0] >> 6 & 0b11
     }
@@ -1,8 +1,10 @@
 use std::{
-    io::{self, Read},
-    net::TcpStream,
+    collections::HashMap,
+    io::{self, Read, Write},
 };
 
+use bytes::Bytes;
+
 use crate::{
     basic_header::{BasicHeader, BasicHeader1, BasicHeader2, BasicHeader3},
     message_header::{
@@ -11,26 +13,120 @@ use crate::{
     },
 };
 
+// https://rtmp.veriskope.com/docs/spec/#541set-chunk-size-1
+pub const DEFAULT_CHUNK_SIZE: u32 = 128;
+// largest message length a header can express
+pub const MAX_CHUNK_SIZE: u32 = 0xFFFFFF;
+
+// Header state of the last chunk seen on a chunk stream, fmt 1/2/3 chunks
+// only carry what changed and inherit the rest from here.
+#[derive(Debug, Clone)]
+pub struct PrecedingChunkAttributes {
+    pub timestamp: u32, // absolute
+    pub timestamp_delta: u32,
+    pub message_length: u32,
+    pub message_type_id: u8,
+    pub message_stream_id: u32,
+    // whether the last type 0/1/2 header used the extended timestamp, in which
+    // case the type 3 chunks following it repeat it
+    pub extended_timestamp: bool,
+}
+impl PrecedingChunkAttributes {
+    // `continuation` tells whether a fmt 3 chunk continues a message that is still
+    // being reassembled (same header) or starts a new one (timestamp advances by
+    // the last delta).
+    pub fn resolve(
+        preceding: Option<&Self>,
+        chunk: &Chunk,
+        continuation: bool,
+    ) -> io::Result<Self> {
+        let fmt = chunk.basic_header.get_fmt();
+        let header = &chunk.message_header;
+        if fmt == 0 {
+            return Ok(Self {
+                timestamp: chunk.get_timestamp().unwrap(),
+                timestamp_delta: 0,
+                message_length: header.get_message_length().unwrap(),
+                message_type_id: header.get_message_type_id().unwrap(),
+                message_stream_id: header.get_message_stream_id().unwrap(),
+                extended_timestamp: chunk.extended_timestamp.is_some(),
+            });
+        }
+        let Some(preceding) = preceding else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "fmt {} chunk on chunk stream {} without a preceding fmt 0 header",
+                    fmt,
+                    chunk.basic_header.get_cs_id()
+                ),
+            ));
+        };
+        let mut attributes = preceding.clone();
+        match fmt {
+            1 | 2 => {
+                attributes.timestamp_delta = chunk.get_timestamp_delta().unwrap();
+                attributes.extended_timestamp = chunk.extended_timestamp.is_some();
+                attributes.timestamp = preceding.timestamp.wrapping_add(attributes.timestamp_delta);
+                if fmt == 1 {
+                    attributes.message_length = header.get_message_length().unwrap();
+                    attributes.message_type_id = header.get_message_type_id().unwrap();
+                }
+            }
+            _ => {
+                if !continuation {
+                    attributes.timestamp =
+                        preceding.timestamp.wrapping_add(preceding.timestamp_delta);
+                }
+            }
+        }
+        Ok(attributes)
+    }
+}
+
 #[derive(Clone)]
-pub(crate) struct Chunk {
+pub struct Chunk {
     pub basic_header: Box<dyn BasicHeader>,
     pub message_header: Box<dyn MessageHeader>,
-    // pub extended_timestamp: Option<[u8; 4]>,
-    pub chunk_data: Vec<u8>,
+    pub extended_timestamp: Option<[u8; 4]>,
+    pub chunk_data: Bytes,
 }
 impl Chunk {
     pub fn new(
         basic_header: Box<dyn BasicHeader>,
         message_header: Box<dyn MessageHeader>,
-        chunk_data: Vec<u8>,
+        extended_timestamp: Option<[u8; 4]>,
+        chunk_data: Bytes,
     ) -> Self {
         Self {
             basic_header,
             message_header,
+            extended_timestamp,
             chunk_data,
         }
     }
-    pub fn read_from_stream(stream: &mut TcpStream) -> io::Result<Self> {
+    pub fn get_extended_timestamp(&self) -> Option<u32> {
+        self.extended_timestamp.map(u32::from_be_bytes)
+    }
+    // Timestamp of a type 0 header, taken from the extended timestamp if needed.
+    pub fn get_timestamp(&self) -> Option<u32> {
+        self.message_header
+            .get_timestamp()
+            .map(|timestamp| self.get_extended_timestamp().unwrap_or(timestamp))
+    }
+    // Timestamp delta of a type 1/2 header, taken from the extended timestamp if needed.
+    pub fn get_timestamp_delta(&self) -> Option<u32> {
+        self.message_header
+            .get_timestamp_delta()
+            .map(|timestamp_delta| self.get_extended_timestamp().unwrap_or(timestamp_delta))
+    }
+    // Reads the basic header and the message header only. How many payload bytes
+    // follow depends on the chunk size and on what is left of the message on this
+    // chunk stream, which only the decoder knows.
+    pub fn read_header_from_stream<R: Read>(
+        stream: &mut R,
+        preceding: &HashMap<u32, PrecedingChunkAttributes>,
+    ) -> io::Result<Self> {
         let mut first_byte = [0u8; 1];
         stream.read_exact(&mut first_byte)?;
 
@@ -51,7 +147,6 @@ impl Chunk {
             }
             _ => Box::new(BasicHeader1::new(first_byte)),
         };
-        println!("fmt: {:?}, cs_id: {}", basic_header.get_fmt(), basic_header.get_cs_id());
         let message_header: Box<dyn MessageHeader> = match basic_header.get_fmt() {
             0 => {
                 let mut next_11_bytes = [0u8; 11];
@@ -71,18 +166,34 @@ impl Chunk {
             _ => Box::new(MessageHeaderType3::new([])),
         };
 
-        let chunk_data = if let Some(message_length) = message_header.get_message_length() {
-            let mut data = vec![0u8; message_length as usize];
-            stream.read_exact(&mut data)?;
-            data
+        let extended = match basic_header.get_fmt() {
+            3 => preceding
+                .get(&basic_header.get_cs_id())
+                .is_some_and(|attributes| attributes.extended_timestamp),
+            _ => message_header.has_extended_timestamp(),
+        };
+        let extended_timestamp = if extended {
+            let mut next_4_bytes = [0u8; 4];
+            stream.read_exact(&mut next_4_bytes)?;
+            Some(next_4_bytes)
         } else {
-            vec![]
+            None
         };
 
         Ok(Self {
             basic_header,
             message_header,
-            chunk_data,
+            extended_timestamp,
+            chunk_data: Bytes::new(),
         })
     }
+    pub fn write_to_stream<W: Write>(&self, stream: &mut W) -> io::Result<()> {
+        stream.write_all(self.basic_header.as_bytes())?;
+        stream.write_all(self.message_header.as_bytes())?;
+        if let Some(extended_timestamp) = self.extended_timestamp {
+            stream.write_all(&extended_timestamp)?;
+        }
+        stream.write_all(&self.chunk_data)?;
+        Ok(())
+    }
 }
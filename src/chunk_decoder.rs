@@ -0,0 +1,230 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use crate::{
+    chunk::{Chunk, DEFAULT_CHUNK_SIZE, PrecedingChunkAttributes},
+    message::{ABORT, SET_CHUNK_SIZE, SetChunkSize},
+};
+
+// A complete message reassembled from one or more chunks.
+#[derive(Debug, Clone)]
+pub struct RawMessage {
+    pub cs_id: u32,
+    pub header: PrecedingChunkAttributes,
+    pub payload: Bytes,
+}
+
+enum Progress {
+    NeedMoreData,
+    Partial,
+    Complete(RawMessage),
+}
+
+// Push based chunk stream decoder, it doesn't do any IO itself so the same
+// parsing works for blocking sockets, async runtimes or in-memory buffers.
+//
+//     decoder.push(&bytes_from_anywhere);
+//     while let Some(message) = decoder.next_message()? { ... }
+//
+// Set Chunk Size and Abort Message are chunk stream level controls and are
+// applied by the decoder itself before the next chunk is parsed, they are still
+// handed out like any other message.
+pub struct ChunkDecoder {
+    buffer: BytesMut,
+    preceding: HashMap<u32, PrecedingChunkAttributes>,
+    // payload received so far for messages that span several chunks, by cs_id
+    incomplete: HashMap<u32, BytesMut>,
+    chunk_size: u32,
+}
+impl Default for ChunkDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl ChunkDecoder {
+    pub fn new() -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            preceding: HashMap::new(),
+            incomplete: HashMap::new(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+    pub fn get_chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+    // Same checks as a Set Chunk Size message received from the peer.
+    pub fn set_chunk_size(&mut self, chunk_size: u32) -> io::Result<()> {
+        self.chunk_size = SetChunkSize::new_from_values(chunk_size).validate()?;
+        Ok(())
+    }
+    // Drops the partially received message on `cs_id`.
+    pub fn abort(&mut self, cs_id: u32) {
+        self.incomplete.remove(&cs_id);
+    }
+    pub fn push(&mut self, data: impl AsRef<[u8]>) {
+        self.buffer.extend_from_slice(data.as_ref());
+    }
+    // Pushes `data` and returns every message it completes.
+    pub fn decode(&mut self, data: impl AsRef<[u8]>) -> io::Result<Vec<RawMessage>> {
+        self.push(data);
+        let mut messages = vec![];
+        while let Some(message) = self.next_message()? {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+    // Returns the next complete message, or `None` once the buffered bytes run
+    // out in the middle of a chunk.
+    pub fn next_message(&mut self) -> io::Result<Option<RawMessage>> {
+        loop {
+            match self.next_chunk()? {
+                Progress::NeedMoreData => return Ok(None),
+                Progress::Partial => {}
+                Progress::Complete(message) => {
+                    self.apply_control(&message)?;
+                    return Ok(Some(message));
+                }
+            }
+        }
+    }
+    // Consumes one chunk if it is fully buffered.
+    fn next_chunk(&mut self) -> io::Result<Progress> {
+        let mut cursor = Cursor::new(&self.buffer[..]);
+        let chunk = match Chunk::read_header_from_stream(&mut cursor, &self.preceding) {
+            Ok(chunk) => chunk,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(Progress::NeedMoreData);
+            }
+            Err(e) => return Err(e),
+        };
+        let header_length = cursor.position() as usize;
+
+        let fmt = chunk.basic_header.get_fmt();
+        let cs_id = chunk.basic_header.get_cs_id();
+        // A fmt 0/1/2 header always starts a new message, only a fmt 3 chunk
+        // can continue the one in progress on this chunk stream.
+        let continuation = fmt == 3 && self.incomplete.contains_key(&cs_id);
+        let header =
+            PrecedingChunkAttributes::resolve(self.preceding.get(&cs_id), &chunk, continuation)?;
+        let received = match continuation {
+            true => self.incomplete[&cs_id].len(),
+            false => 0,
+        };
+        let data_length = (header.message_length as usize - received).min(self.chunk_size as usize);
+        if self.buffer.len() < header_length + data_length {
+            return Ok(Progress::NeedMoreData);
+        }
+
+        self.buffer.advance(header_length);
+        let chunk_data = self.buffer.split_to(data_length);
+        self.preceding.insert(cs_id, header.clone());
+
+        let payload = match (continuation, self.incomplete.remove(&cs_id)) {
+            (true, Some(mut payload)) => {
+                payload.unsplit(chunk_data);
+                payload
+            }
+            _ => chunk_data,
+        };
+        if payload.len() < header.message_length as usize {
+            self.incomplete.insert(cs_id, payload);
+            return Ok(Progress::Partial);
+        }
+        Ok(Progress::Complete(RawMessage {
+            cs_id,
+            header,
+            payload: payload.freeze(),
+        }))
+    }
+    fn apply_control(&mut self, message: &RawMessage) -> io::Result<()> {
+        match (message.header.message_type_id, &message.payload[..]) {
+            (SET_CHUNK_SIZE, &[a, b, c, d]) => {
+                self.set_chunk_size(u32::from_be_bytes([a, b, c, d]))?
+            }
+            (ABORT, &[a, b, c, d]) => self.abort(u32::from_be_bytes([a, b, c, d])),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunk_writer::ChunkWriter,
+        message::{AbortMessage, PROTOCOL_CONTROL_CS_ID},
+    };
+
+    #[test]
+    fn applies_set_chunk_size() {
+        let mut writer = ChunkWriter::new();
+        let set_chunk_size = SetChunkSize::new_from_values(4096);
+        writer
+            .write_message(
+                PROTOCOL_CONTROL_CS_ID,
+                0,
+                SET_CHUNK_SIZE,
+                0,
+                set_chunk_size.get_payload(),
+            )
+            .unwrap();
+        writer.set_chunk_size(4096).unwrap();
+        let payload = vec![3u8; 3000];
+        writer.write_message(4, 0, 9, 1, &payload).unwrap();
+
+        let mut decoder = ChunkDecoder::new();
+        let messages = decoder.decode(writer.take_output()).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(decoder.get_chunk_size(), 4096);
+        assert_eq!(messages[1].payload, payload);
+    }
+
+    #[test]
+    fn rejects_invalid_chunk_size() {
+        let mut writer = ChunkWriter::new();
+        writer
+            .write_message(PROTOCOL_CONTROL_CS_ID, 0, SET_CHUNK_SIZE, 0, &[0, 0, 0, 0])
+            .unwrap();
+        assert!(ChunkDecoder::new().decode(writer.take_output()).is_err());
+
+        let mut decoder = ChunkDecoder::new();
+        assert!(decoder.set_chunk_size(0).is_err());
+        assert!(decoder.set_chunk_size(0x80000000).is_err());
+        assert_eq!(decoder.get_chunk_size(), DEFAULT_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn abort_drops_partial_message() {
+        let mut writer = ChunkWriter::new();
+        let mut decoder = ChunkDecoder::new();
+        // fmt 0 then fmt 2, so the next messages on the stream go as fmt 3
+        for timestamp in [0, 10] {
+            writer
+                .write_message(4, timestamp, 9, 1, &[0u8; 300])
+                .unwrap();
+        }
+        assert_eq!(decoder.decode(writer.take_output()).unwrap().len(), 2);
+
+        // only the first chunk of the aborted message arrives
+        writer.write_message(4, 20, 9, 1, &[1u8; 300]).unwrap();
+        let output = writer.take_output();
+        assert_eq!(output[0] >> 6, 3);
+        assert!(decoder.decode(&output[..1 + 128]).unwrap().is_empty());
+
+        let abort = AbortMessage::new_from_values(4);
+        writer
+            .write_message(PROTOCOL_CONTROL_CS_ID, 0, ABORT, 0, abort.get_payload())
+            .unwrap();
+        writer.write_message(4, 30, 9, 1, &[2u8; 300]).unwrap();
+        let messages = decoder.decode(writer.take_output()).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].header.timestamp, 30);
+        assert_eq!(messages[1].payload, vec![2u8; 300]);
+    }
+}
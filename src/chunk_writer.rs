@@ -0,0 +1,227 @@
+use std::{collections::HashMap, io};
+
+use bytes::Bytes;
+
+use crate::{
+    basic_header::new_basic_header,
+    chunk::{Chunk, DEFAULT_CHUNK_SIZE, PrecedingChunkAttributes},
+    message::SetChunkSize,
+    message_header::{
+        EXTENDED_TIMESTAMP, MessageHeader, MessageHeaderType0, MessageHeaderType1,
+        MessageHeaderType2, MessageHeaderType3,
+    },
+};
+
+// Splits outbound messages into chunks, compressing each header against the
+// previous one sent on the same chunk stream. The encoded chunks are buffered
+// until the owner of the connection collects them with `take_output`.
+pub struct ChunkWriter {
+    output: Vec<u8>,
+    chunk_size: u32,
+    // header state and fmt of the last message sent, by cs_id
+    preceding: HashMap<u32, (u8, PrecedingChunkAttributes)>,
+}
+impl Default for ChunkWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl ChunkWriter {
+    pub fn new() -> Self {
+        Self {
+            output: vec![],
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            preceding: HashMap::new(),
+        }
+    }
+    pub fn get_chunk_size(&self) -> u32 {
+        self.chunk_size
+    }
+    // Only takes effect for messages written afterwards, the peer has to be told
+    // with a Set Chunk Size message first. Rejects the sizes the peer would
+    // reject in that message.
+    pub fn set_chunk_size(&mut self, chunk_size: u32) -> io::Result<()> {
+        self.chunk_size = SetChunkSize::new_from_values(chunk_size).validate()?;
+        Ok(())
+    }
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    pub fn write_message(
+        &mut self,
+        cs_id: u32,
+        timestamp: u32,
+        message_type_id: u8,
+        message_stream_id: u32,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let message_length = payload.len() as u32;
+        let preceding = self.preceding.get(&cs_id);
+        let timestamp_delta = preceding
+            .map(|(_, attributes)| timestamp.wrapping_sub(attributes.timestamp))
+            .unwrap_or(0);
+        let fmt = match preceding {
+            Some((preceding_fmt, attributes))
+                if attributes.message_stream_id == message_stream_id
+                    && timestamp >= attributes.timestamp =>
+            {
+                if attributes.message_length != message_length
+                    || attributes.message_type_id != message_type_id
+                {
+                    1
+                // Peers disagree on what a type 3 header means right after a type 0
+                // one (some add the absolute timestamp again), so only reuse a delta
+                // that was actually sent.
+                } else if *preceding_fmt == 0 || attributes.timestamp_delta != timestamp_delta {
+                    2
+                } else {
+                    3
+                }
+            }
+            _ => 0,
+        };
+
+        // the value carried by the timestamp field, repeated as extended timestamp
+        // by every type 3 chunk of the message if it doesn't fit in 24 bits
+        let (field, message_header): (u32, Box<dyn MessageHeader>) = match fmt {
+            0 => (
+                timestamp,
+                Box::new(MessageHeaderType0::new_from_values(
+                    timestamp,
+                    message_length,
+                    message_type_id,
+                    message_stream_id,
+                )),
+            ),
+            1 => (
+                timestamp_delta,
+                Box::new(MessageHeaderType1::new_from_values(
+                    timestamp_delta,
+                    message_length,
+                    message_type_id,
+                )),
+            ),
+            2 => (
+                timestamp_delta,
+                Box::new(MessageHeaderType2::new_from_values(timestamp_delta)),
+            ),
+            _ => (timestamp_delta, Box::new(MessageHeaderType3::new([]))),
+        };
+        let extended_timestamp = (field >= EXTENDED_TIMESTAMP).then(|| u32::to_be_bytes(field));
+
+        let mut chunks = payload.chunks(self.chunk_size as usize);
+        Chunk::new(
+            new_basic_header(fmt, cs_id)?,
+            message_header,
+            extended_timestamp,
+            Bytes::copy_from_slice(chunks.next().unwrap_or_default()),
+        )
+        .write_to_stream(&mut self.output)?;
+        for chunk_data in chunks {
+            Chunk::new(
+                new_basic_header(3, cs_id)?,
+                Box::new(MessageHeaderType3::new([])),
+                extended_timestamp,
+                Bytes::copy_from_slice(chunk_data),
+            )
+            .write_to_stream(&mut self.output)?;
+        }
+
+        self.preceding.insert(
+            cs_id,
+            (
+                fmt,
+                PrecedingChunkAttributes {
+                    timestamp,
+                    timestamp_delta: if fmt == 0 { 0 } else { timestamp_delta },
+                    message_length,
+                    message_type_id,
+                    message_stream_id,
+                    extended_timestamp: extended_timestamp.is_some(),
+                },
+            ),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk_decoder::{ChunkDecoder, RawMessage};
+
+    // Writes one message and decodes it back, also returns the fmt of its
+    // first chunk.
+    fn round_trip(
+        writer: &mut ChunkWriter,
+        decoder: &mut ChunkDecoder,
+        cs_id: u32,
+        timestamp: u32,
+        payload: &[u8],
+    ) -> (u8, RawMessage) {
+        writer
+            .write_message(cs_id, timestamp, 9, 1, payload)
+            .unwrap();
+        let output = writer.take_output();
+        let mut messages = decoder.decode(&output).unwrap();
+        assert_eq!(messages.len(), 1);
+        (output[0] >> 6, messages.remove(0))
+    }
+
+    #[test]
+    fn round_trips_cs_ids() {
+        let mut writer = ChunkWriter::new();
+        let mut decoder = ChunkDecoder::new();
+        let payload = vec![7u8; 300];
+        for (cs_id, basic_header) in [(2, 2), (63, 63), (64, 0), (319, 0), (320, 1), (65599, 1)] {
+            writer.write_message(cs_id, 0, 9, 1, &payload).unwrap();
+            let output = writer.take_output();
+            assert_eq!(output[0] & 0x3F, basic_header, "cs_id {}", cs_id);
+            let messages = decoder.decode(&output).unwrap();
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].cs_id, cs_id);
+            assert_eq!(messages[0].payload, payload);
+        }
+    }
+
+    #[test]
+    fn round_trips_extended_timestamps() {
+        let mut writer = ChunkWriter::new();
+        let mut decoder = ChunkDecoder::new();
+        // every message spans several chunks, each with the extended timestamp
+        let long = vec![1u8; 300];
+        let short = vec![2u8; 200];
+        for (timestamp, payload, fmt) in [
+            (0x1000000, &long, 0),
+            (0x2000000, &short, 1),
+            (0x3000000, &short, 3),
+            (0x3000001, &short, 2),
+        ] {
+            let (first_fmt, message) = round_trip(&mut writer, &mut decoder, 4, timestamp, payload);
+            assert_eq!(first_fmt, fmt, "timestamp {:#x}", timestamp);
+            assert_eq!(message.header.timestamp, timestamp);
+            assert_eq!(&message.payload[..], &payload[..]);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_cs_id() {
+        let mut writer = ChunkWriter::new();
+        for cs_id in [0, 1, 65600] {
+            assert!(writer.write_message(cs_id, 0, 20, 0, b"payload").is_err());
+        }
+        assert!(writer.take_output().is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_chunk_size() {
+        let mut writer = ChunkWriter::new();
+        assert!(writer.set_chunk_size(0).is_err());
+        assert!(writer.set_chunk_size(0x80000000).is_err());
+        assert_eq!(writer.get_chunk_size(), DEFAULT_CHUNK_SIZE);
+        // one byte per chunk, the two type 3 chunks only add a basic header
+        writer.set_chunk_size(1).unwrap();
+        writer.write_message(3, 0, 9, 1, &[1, 2, 3]).unwrap();
+        assert_eq!(writer.take_output().len(), 12 + 1 + 2 * 2);
+    }
+}
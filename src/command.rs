@@ -0,0 +1,1123 @@
+use std::{
+    io::{self, Cursor},
+    sync::Arc,
+    time::Duration,
+};
+
+use amf::{Amf0Value, Pair};
+use bytes::Bytes;
+
+use crate::{
+    message::{
+        AcknowledgementWindowSize, COMMAND_CS_ID, CommandMessageAmf0, DATA_CS_ID,
+        PROTOCOL_CONTROL_CS_ID, RtmpMessage, SetChunkSize, SetPeerBandwidth, UserControlEvent,
+        UserControlMessage,
+    },
+    session::Session,
+    subscription::Subscription,
+};
+
+// An AMF0 command: name, transaction id, command object (Null for most
+// commands other than connect) and the optional arguments.
+// https://rtmp.veriskope.com/docs/spec/#72command-messages-20-17
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    pub name: String,
+    pub transaction_id: f64,
+    pub command_object: Amf0Value,
+    pub arguments: Vec<Amf0Value>,
+}
+impl Command {
+    pub fn new(
+        name: &str,
+        transaction_id: f64,
+        command_object: Amf0Value,
+        arguments: Vec<Amf0Value>,
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            transaction_id,
+            command_object,
+            arguments,
+        }
+    }
+    pub fn decode(payload: &[u8]) -> io::Result<Self> {
+        let mut cursor = Cursor::new(payload);
+        let mut values = vec![];
+        while (cursor.position() as usize) < payload.len() {
+            let value = Amf0Value::read_from(&mut cursor)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            values.push(value);
+        }
+        let mut values = values.into_iter();
+        let (Some(Amf0Value::String(name)), Some(Amf0Value::Number(transaction_id))) =
+            (values.next(), values.next())
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "command doesn't start with a name and a transaction id",
+            ));
+        };
+        Ok(Self {
+            name,
+            transaction_id,
+            command_object: values.next().unwrap_or(Amf0Value::Null),
+            arguments: values.collect(),
+        })
+    }
+    // Command AMF3 (17) payload: a format byte, always 0, then the same AMF0
+    // values, any of which can switch to AMF3 with the avmplus marker.
+    pub fn decode_amf3(payload: &[u8]) -> io::Result<Self> {
+        match payload.split_first() {
+            Some((0, payload)) => Self::decode(payload),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "AMF3 command without format byte 0",
+            )),
+        }
+    }
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+        Amf0Value::String(self.name.clone()).write_to(&mut payload)?;
+        Amf0Value::Number(self.transaction_id).write_to(&mut payload)?;
+        self.command_object.write_to(&mut payload)?;
+        for argument in &self.arguments {
+            argument.write_to(&mut payload)?;
+        }
+        Ok(payload)
+    }
+}
+
+// Anonymous AMF0 object from key/value pairs.
+pub fn object(entries: Vec<(&str, Amf0Value)>) -> Amf0Value {
+    amf::amf0::object(entries.into_iter())
+}
+
+fn pairs(value: &Amf0Value) -> Option<&[Pair<String, Amf0Value>]> {
+    match value {
+        Amf0Value::Object { entries, .. } | Amf0Value::EcmaArray { entries } => Some(entries),
+        _ => None,
+    }
+}
+
+// Command object of NetConnection.connect.
+// https://rtmp.veriskope.com/docs/spec/#7211connect
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConnectParams {
+    pub app: String,
+    pub flash_ver: Option<String>,
+    pub swf_url: Option<String>,
+    pub tc_url: Option<String>,
+    pub fpad: Option<bool>,
+    pub audio_codecs: Option<f64>,
+    pub video_codecs: Option<f64>,
+    // 0 for AMF0, 3 for AMF3
+    pub object_encoding: f64,
+    // command object properties not listed above, e.g. `type` sent by encoders
+    pub properties: Vec<(String, Amf0Value)>,
+    // optional user arguments following the command object
+    pub arguments: Vec<Amf0Value>,
+}
+impl ConnectParams {
+    pub fn from_command(command: &Command) -> io::Result<Self> {
+        let Some(entries) = pairs(&command.command_object) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "connect without a command object",
+            ));
+        };
+        let mut params = ConnectParams {
+            arguments: command.arguments.clone(),
+            ..Default::default()
+        };
+        let mut app = None;
+        for Pair { key, value } in entries {
+            let string = || value.try_as_str().map(str::to_string);
+            match key.as_str() {
+                "app" => app = string(),
+                "flashVer" => params.flash_ver = string(),
+                "swfUrl" => params.swf_url = string(),
+                "tcUrl" => params.tc_url = string(),
+                "fpad" => {
+                    params.fpad = match value {
+                        Amf0Value::Boolean(fpad) => Some(*fpad),
+                        _ => None,
+                    }
+                }
+                "audioCodecs" => params.audio_codecs = value.try_as_f64(),
+                "videoCodecs" => params.video_codecs = value.try_as_f64(),
+                "objectEncoding" => params.object_encoding = value.try_as_f64().unwrap_or(0.0),
+                _ => params.properties.push((key.clone(), value.clone())),
+            }
+        }
+        let Some(app) = app else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "connect command object without app",
+            ));
+        };
+        // clients differ on a trailing slash
+        params.app = app.trim_end_matches('/').to_string();
+        Ok(params)
+    }
+}
+
+fn string_argument(command: &Command, index: usize) -> io::Result<String> {
+    match command.arguments.get(index) {
+        Some(Amf0Value::String(value)) => Ok(value.clone()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} without a string argument {}", command.name, index),
+        )),
+    }
+}
+fn number_argument(command: &Command, index: usize) -> Option<f64> {
+    command.arguments.get(index).and_then(Amf0Value::try_as_f64)
+}
+fn bool_argument(command: &Command, index: usize) -> Option<bool> {
+    match command.arguments.get(index) {
+        Some(Amf0Value::Boolean(value)) => Some(*value),
+        _ => None,
+    }
+}
+
+// NetConnection and NetStream commands with their arguments.
+// https://rtmp.veriskope.com/docs/spec/#721netconnection-commands
+// https://rtmp.veriskope.com/docs/spec/#722netstream-commands
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetCommand {
+    Connect(ConnectParams),
+    Call {
+        procedure_name: String,
+    },
+    Close,
+    CreateStream,
+    DeleteStream {
+        stream_id: u32,
+    },
+    ReleaseStream {
+        stream_name: String,
+    },
+    FcPublish {
+        stream_name: String,
+    },
+    FcUnpublish {
+        stream_name: String,
+    },
+    // publish_type is live, record or append
+    Publish {
+        stream_name: String,
+        publish_type: String,
+    },
+    // start -2 plays live or recorded, -1 live only, >= 0 recorded from there,
+    // duration -1 plays until the end
+    Play {
+        stream_name: String,
+        start: f64,
+        duration: f64,
+        reset: bool,
+    },
+    Play2 {
+        parameters: Amf0Value,
+    },
+    Pause {
+        pause: bool,
+        milliseconds: f64,
+    },
+    Seek {
+        milliseconds: f64,
+    },
+    ReceiveAudio(bool),
+    ReceiveVideo(bool),
+    CloseStream,
+    GetStreamLength {
+        stream_name: String,
+    },
+    Unknown,
+}
+impl NetCommand {
+    pub fn from_command(command: &Command) -> io::Result<Self> {
+        Ok(match command.name.as_str() {
+            "connect" => NetCommand::Connect(ConnectParams::from_command(command)?),
+            "call" => NetCommand::Call {
+                procedure_name: string_argument(command, 0)?,
+            },
+            "close" => NetCommand::Close,
+            "createStream" => NetCommand::CreateStream,
+            "deleteStream" => NetCommand::DeleteStream {
+                stream_id: number_argument(command, 0).unwrap_or(0.0) as u32,
+            },
+            "releaseStream" => NetCommand::ReleaseStream {
+                stream_name: string_argument(command, 0)?,
+            },
+            "FCPublish" => NetCommand::FcPublish {
+                stream_name: string_argument(command, 0)?,
+            },
+            "FCUnpublish" => NetCommand::FcUnpublish {
+                stream_name: string_argument(command, 0)?,
+            },
+            "publish" => NetCommand::Publish {
+                stream_name: string_argument(command, 0)?,
+                publish_type: string_argument(command, 1).unwrap_or_else(|_| "live".to_string()),
+            },
+            "play" => NetCommand::Play {
+                stream_name: string_argument(command, 0)?,
+                start: number_argument(command, 1).unwrap_or(-2.0),
+                duration: number_argument(command, 2).unwrap_or(-1.0),
+                reset: bool_argument(command, 3).unwrap_or(true),
+            },
+            "play2" => NetCommand::Play2 {
+                parameters: command
+                    .arguments
+                    .first()
+                    .cloned()
+                    .unwrap_or(Amf0Value::Null),
+            },
+            "pause" => NetCommand::Pause {
+                pause: bool_argument(command, 0).unwrap_or(true),
+                milliseconds: number_argument(command, 1).unwrap_or(0.0),
+            },
+            "seek" => NetCommand::Seek {
+                milliseconds: number_argument(command, 0).unwrap_or(0.0),
+            },
+            "receiveAudio" => NetCommand::ReceiveAudio(bool_argument(command, 0).unwrap_or(true)),
+            "receiveVideo" => NetCommand::ReceiveVideo(bool_argument(command, 0).unwrap_or(true)),
+            "closeStream" => NetCommand::CloseStream,
+            "getStreamLength" => NetCommand::GetStreamLength {
+                stream_name: string_argument(command, 0)?,
+            },
+            _ => NetCommand::Unknown,
+        })
+    }
+}
+
+// Info object of `_error` and onStatus.
+pub fn status(level: &str, code: &str, description: &str) -> Amf0Value {
+    object(vec![
+        ("level", Amf0Value::String(level.to_string())),
+        ("code", Amf0Value::String(code.to_string())),
+        ("description", Amf0Value::String(description.to_string())),
+    ])
+}
+
+// Sends a command on the command chunk stream.
+pub fn send_command(
+    session: &mut Session,
+    message_stream_id: u32,
+    command: &Command,
+) -> io::Result<()> {
+    RtmpMessage::CommandAmf0(CommandMessageAmf0::new_from_command(command)?).write(
+        session.get_writer(),
+        COMMAND_CS_ID,
+        0,
+        message_stream_id,
+    )
+}
+
+fn send_result(
+    session: &mut Session,
+    message_stream_id: u32,
+    command: &Command,
+    arguments: Vec<Amf0Value>,
+) -> io::Result<()> {
+    let result = Command::new(
+        "_result",
+        command.transaction_id,
+        Amf0Value::Null,
+        arguments,
+    );
+    send_command(session, message_stream_id, &result)
+}
+
+// onStatus notification on a NetStream, it doesn't answer a transaction.
+pub fn send_status(
+    session: &mut Session,
+    message_stream_id: u32,
+    level: &str,
+    code: &str,
+    description: &str,
+) -> io::Result<()> {
+    let on_status = Command::new(
+        "onStatus",
+        0.0,
+        Amf0Value::Null,
+        vec![status(level, code, description)],
+    );
+    send_command(session, message_stream_id, &on_status)
+}
+
+fn send_error(
+    session: &mut Session,
+    message_stream_id: u32,
+    command: &Command,
+    code: &str,
+    description: &str,
+) -> io::Result<()> {
+    let error = Command::new(
+        "_error",
+        command.transaction_id,
+        Amf0Value::Null,
+        vec![status("error", code, description)],
+    );
+    send_command(session, message_stream_id, &error)
+}
+
+// Routes a command received on `message_stream_id` to its handler.
+pub fn dispatch(
+    session: &mut Session,
+    message_stream_id: u32,
+    command: &Command,
+) -> io::Result<()> {
+    println!("Command {} ({})", command.name, command.transaction_id);
+    let net_command = match NetCommand::from_command(command) {
+        Ok(net_command) => net_command,
+        Err(e) => {
+            // only this call fails, the connection stays usable
+            eprintln!("{}", e);
+            let code = match command.name.as_str() {
+                "connect" => "NetConnection.Connect.Rejected",
+                _ => "NetConnection.Call.Failed",
+            };
+            return send_error(session, message_stream_id, command, code, &e.to_string());
+        }
+    };
+    match net_command {
+        NetCommand::Connect(params) => connect(session, command, params),
+        NetCommand::Call { procedure_name } => {
+            // no remote procedures are registered
+            let description = format!("Method not found ({})", procedure_name);
+            send_error(
+                session,
+                message_stream_id,
+                command,
+                "NetConnection.Call.Failed",
+                &description,
+            )
+        }
+        NetCommand::Close => {
+            println!("NetConnection closed by peer");
+            Ok(())
+        }
+        NetCommand::CreateStream => {
+            let stream_id = session.create_stream();
+            println!("createStream {}", stream_id);
+            send_result(
+                session,
+                message_stream_id,
+                command,
+                vec![Amf0Value::Number(stream_id as f64)],
+            )
+        }
+        NetCommand::DeleteStream { stream_id } => {
+            println!("deleteStream {}", stream_id);
+            session.delete_stream(stream_id);
+            Ok(())
+        }
+        NetCommand::ReleaseStream { stream_name } => {
+            println!("releaseStream {}", stream_name);
+            send_result(
+                session,
+                message_stream_id,
+                command,
+                vec![Amf0Value::Undefined],
+            )
+        }
+        NetCommand::FcPublish { stream_name } => {
+            println!("FCPublish {}", stream_name);
+            send_result(
+                session,
+                message_stream_id,
+                command,
+                vec![Amf0Value::Undefined],
+            )
+        }
+        NetCommand::FcUnpublish { stream_name } => {
+            println!("FCUnpublish {}", stream_name);
+            send_result(
+                session,
+                message_stream_id,
+                command,
+                vec![Amf0Value::Undefined],
+            )
+        }
+        NetCommand::Publish {
+            stream_name,
+            publish_type,
+        } => publish(session, message_stream_id, &stream_name, &publish_type),
+        NetCommand::Play {
+            stream_name,
+            start,
+            duration,
+            reset,
+        } => {
+            println!(
+                "play {} (start {}, duration {}, reset {})",
+                stream_name, start, duration, reset
+            );
+            // only live streams, start and duration don't apply
+            play(session, message_stream_id, &stream_name, reset)
+        }
+        NetCommand::Play2 { parameters } => {
+            println!("play2 {:?}", parameters);
+            // switching to another stream, the only transition live streams have
+            let stream_name = pairs(&parameters)
+                .into_iter()
+                .flatten()
+                .find(|pair| pair.key == "streamName")
+                .and_then(|pair| pair.value.try_as_str());
+            match stream_name {
+                Some(stream_name) => play(session, message_stream_id, stream_name, false),
+                None => Ok(()),
+            }
+        }
+        NetCommand::Pause {
+            pause,
+            milliseconds,
+        } => {
+            println!("pause {} at {}", pause, milliseconds);
+            if let Some(stream) = session.get_stream_mut(message_stream_id) {
+                stream.set_paused(pause);
+            }
+            Ok(())
+        }
+        NetCommand::Seek { milliseconds } => {
+            println!("seek {}", milliseconds);
+            Ok(())
+        }
+        NetCommand::ReceiveAudio(receive) => {
+            println!("receiveAudio {}", receive);
+            if let Some(stream) = session.get_stream_mut(message_stream_id) {
+                stream.set_receive_audio(receive);
+            }
+            Ok(())
+        }
+        NetCommand::ReceiveVideo(receive) => {
+            println!("receiveVideo {}", receive);
+            if let Some(stream) = session.get_stream_mut(message_stream_id) {
+                stream.set_receive_video(receive);
+            }
+            Ok(())
+        }
+        NetCommand::CloseStream => {
+            println!("closeStream on {}", message_stream_id);
+            session.delete_stream(message_stream_id);
+            Ok(())
+        }
+        NetCommand::GetStreamLength { stream_name } => {
+            // live streams have no length
+            println!("getStreamLength {}", stream_name);
+            send_result(
+                session,
+                message_stream_id,
+                command,
+                vec![Amf0Value::Number(0.0)],
+            )
+        }
+        NetCommand::Unknown => {
+            let description = format!("Unknown command {}", command.name);
+            send_error(
+                session,
+                message_stream_id,
+                command,
+                "NetConnection.Call.Failed",
+                &description,
+            )
+        }
+    }
+}
+
+// +--------------+                              +-------------+
+// |    Client    |             |                |    Server   |
+// +------+-------+             |                +------+------+
+//        |              Handshaking done               |
+//        |                     |                       |
+//        |                     |                       |
+//        |                     |                       |
+//        |                     |                       |
+//        |----------- Command Message(connect) ------->|
+//        |                                             |
+//        |<------- Window Acknowledgement Size --------|
+//        |                                             |
+//        |<----------- Set Peer Bandwidth -------------|
+//        |                                             |
+//        |-------- Window Acknowledgement Size ------->|
+//        |                                             |
+//        |<------ User Control Message(StreamBegin) ---|
+//        |                                             |
+//        |<------------ Command Message ---------------|
+//        |       (_result- connect response)           |
+//        |                                             |
+fn connect(session: &mut Session, command: &Command, params: ConnectParams) -> io::Result<()> {
+    println!("{:?}", params);
+    let chunk_size = session.get_config().get_chunk_size()?;
+    session
+        .get_flow_control()
+        .on_window_acknowledgement_size_sent(5000000);
+    let writer = session.get_writer();
+
+    RtmpMessage::WindowAcknowledgementSize(AcknowledgementWindowSize::new_from_values(5000000))
+        .write(writer, PROTOCOL_CONTROL_CS_ID, 0, 0)?;
+    println!("acknowledgement_window_size sent successfully.");
+
+    RtmpMessage::SetPeerBandwidth(SetPeerBandwidth::new_from_values(5000000, 2)).write(
+        writer,
+        PROTOCOL_CONTROL_CS_ID,
+        0,
+        0,
+    )?;
+    println!("set_peer_bandwidth sent successfully.");
+
+    // from here on larger chunks cut the per chunk overhead
+    RtmpMessage::SetChunkSize(SetChunkSize::new_from_values(chunk_size)).write(
+        writer,
+        PROTOCOL_CONTROL_CS_ID,
+        0,
+        0,
+    )?;
+    writer.set_chunk_size(chunk_size)?;
+    println!("set_chunk_size sent successfully.");
+
+    // the NetConnection itself is message stream 0
+    RtmpMessage::UserControl(UserControlMessage::new_from_event(
+        &UserControlEvent::StreamBegin(0),
+    ))
+    .write(writer, PROTOCOL_CONTROL_CS_ID, 0, 0)?;
+    println!("stream_begin sent successfully.");
+
+    let response = Command::new(
+        "_result",
+        command.transaction_id,
+        object(vec![
+            ("fmsVer", Amf0Value::String("FMS/3,5,7,7007".to_string())),
+            ("capabilities", Amf0Value::Number(31.0)),
+            ("mode", Amf0Value::Number(1.0)),
+        ]),
+        vec![object(vec![
+            ("level", Amf0Value::String("status".to_string())),
+            (
+                "code",
+                Amf0Value::String("NetConnection.Connect.Success".to_string()),
+            ),
+            (
+                "description",
+                Amf0Value::String("Connection succeeded.".to_string()),
+            ),
+            ("objectEncoding", Amf0Value::Number(params.object_encoding)),
+        ])],
+    );
+    RtmpMessage::CommandAmf0(CommandMessageAmf0::new_from_command(&response)?).write(
+        writer,
+        COMMAND_CS_ID,
+        0,
+        0,
+    )?;
+    println!("response sent successfully.");
+    session.set_connect_params(params);
+    Ok(())
+}
+
+// Only live publishing, record and append are treated the same.
+fn publish(
+    session: &mut Session,
+    message_stream_id: u32,
+    stream_name: &str,
+    publish_type: &str,
+) -> io::Result<()> {
+    println!("publish {} ({})", stream_name, publish_type);
+    // encoders often pass credentials as a query string after the name
+    let name = stream_name.split('?').next().unwrap_or_default();
+    // no key before connect
+    let key = match session.get_stream_key(name) {
+        Ok(key) if !name.is_empty() && session.get_stream(message_stream_id).is_some() => key,
+        _ => {
+            let description = format!("Can't publish {} on stream {}.", name, message_stream_id);
+            return send_status(
+                session,
+                message_stream_id,
+                "error",
+                "NetStream.Publish.BadName",
+                &description,
+            );
+        }
+    };
+    // whatever the stream was doing before stops
+    session.stop_stream(message_stream_id);
+    let config = session.get_config();
+    let gop_cache = session
+        .get_connect_params()
+        .and_then(|params| config.get_gop_cache(&params.app));
+    let published = config
+        .registry
+        .lock()
+        .unwrap()
+        .publish(&key, session.get_id(), gop_cache);
+    if !published {
+        let description = format!("{} is already published.", name);
+        return send_status(
+            session,
+            message_stream_id,
+            "error",
+            "NetStream.Publish.BadName",
+            &description,
+        );
+    }
+    if let Some(stream) = session.get_stream_mut(message_stream_id) {
+        stream.set_publishing(name);
+    }
+    println!("Published {}", key);
+    let description = format!("{} is now published.", name);
+    send_status(
+        session,
+        message_stream_id,
+        "status",
+        "NetStream.Publish.Start",
+        &description,
+    )
+}
+
+// Subscribes the stream to a live stream of the connected app.
+fn play(
+    session: &mut Session,
+    message_stream_id: u32,
+    stream_name: &str,
+    reset: bool,
+) -> io::Result<()> {
+    let name = stream_name.split('?').next().unwrap_or_default();
+    // no key before connect
+    let key = match session.get_stream_key(name) {
+        Ok(key) if !name.is_empty() && session.get_stream(message_stream_id).is_some() => key,
+        _ => {
+            let description = format!("Can't play {} on stream {}.", name, message_stream_id);
+            return send_status(
+                session,
+                message_stream_id,
+                "error",
+                "NetStream.Play.Failed",
+                &description,
+            );
+        }
+    };
+    let registry = session.get_config().registry.clone();
+    if !registry.lock().unwrap().is_live(&key) && !session.get_config().wait_for_publisher {
+        let description = format!("{} is not live.", name);
+        return send_status(
+            session,
+            message_stream_id,
+            "error",
+            "NetStream.Play.StreamNotFound",
+            &description,
+        );
+    }
+
+    // whatever the stream was doing before stops
+    session.stop_stream(message_stream_id);
+    let subscription = Arc::new(Subscription::new(
+        session.get_config().subscription,
+        session.get_waker(),
+    ));
+    if let Some(buffer_length) = session.get_buffer_length(message_stream_id) {
+        subscription.set_buffer_length(Duration::from_millis(buffer_length as u64));
+    }
+    registry
+        .lock()
+        .unwrap()
+        .subscribe(&key, subscription.clone());
+    if let Some(stream) = session.get_stream_mut(message_stream_id) {
+        stream.set_playing(name, subscription);
+    }
+    println!("Playing {}", key);
+
+    session.send_user_control(&UserControlEvent::StreamBegin(message_stream_id))?;
+    if reset {
+        let description = format!("Playing and resetting {}.", name);
+        send_status(
+            session,
+            message_stream_id,
+            "status",
+            "NetStream.Play.Reset",
+            &description,
+        )?;
+    }
+    let description = format!("Started playing {}.", name);
+    send_status(
+        session,
+        message_stream_id,
+        "status",
+        "NetStream.Play.Start",
+        &description,
+    )?;
+    // allows Flash players to access the raw audio and video data
+    let mut sample_access = vec![];
+    Amf0Value::String("|RtmpSampleAccess".to_string()).write_to(&mut sample_access)?;
+    Amf0Value::Boolean(true).write_to(&mut sample_access)?;
+    Amf0Value::Boolean(true).write_to(&mut sample_access)?;
+    RtmpMessage::DataAmf0(Bytes::from(sample_access)).write(
+        session.get_writer(),
+        DATA_CS_ID,
+        0,
+        message_stream_id,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        chunk_decoder::{ChunkDecoder, RawMessage},
+        message::{
+            AUDIO, COMMAND_AMF0, DATA_AMF0, SET_CHUNK_SIZE, SET_PEER_BANDWIDTH, USER_CONTROL,
+            VIDEO, WINDOW_ACKNOWLEDGEMENT_SIZE,
+        },
+        registry::MediaMessage,
+        session::SessionConfig,
+    };
+    use std::time::Instant;
+
+    fn responses(session: &mut Session) -> Vec<Command> {
+        let mut decoder = ChunkDecoder::new();
+        decoder
+            .decode(session.take_output())
+            .unwrap()
+            .into_iter()
+            .map(|message| Command::decode(&message.payload).unwrap())
+            .collect()
+    }
+    fn property<'a>(value: &'a Amf0Value, key: &str) -> Option<&'a Amf0Value> {
+        pairs(value)?
+            .iter()
+            .find(|pair| pair.key == key)
+            .map(|pair| &pair.value)
+    }
+    // code of the info object of an _error, _result or onStatus
+    fn code(response: &Command) -> &str {
+        property(response.arguments.last().unwrap(), "code")
+            .and_then(Amf0Value::try_as_str)
+            .unwrap()
+    }
+
+    // A session connected to the "live" app, with a stream created, and the
+    // client end of its chunk stream.
+    struct Client {
+        session: Session,
+        decoder: ChunkDecoder,
+        stream_id: u32,
+    }
+    impl Client {
+        fn new(config: &SessionConfig) -> Self {
+            let mut session = Session::with_config(config.clone());
+            let app = Amf0Value::String("live".to_string());
+            let connect = Command::new("connect", 1.0, object(vec![("app", app)]), vec![]);
+            dispatch(&mut session, 0, &connect).unwrap();
+            let stream_id = session.create_stream();
+            let mut client = Self {
+                session,
+                decoder: ChunkDecoder::new(),
+                stream_id,
+            };
+            client.receive();
+            client
+        }
+        fn send(&mut self, name: &str, arguments: &[&str]) {
+            let arguments = arguments
+                .iter()
+                .map(|argument| Amf0Value::String(argument.to_string()))
+                .collect();
+            let command = Command::new(name, 0.0, Amf0Value::Null, arguments);
+            dispatch(&mut self.session, self.stream_id, &command).unwrap();
+        }
+        fn receive(&mut self) -> Vec<RawMessage> {
+            self.decoder.decode(self.session.take_output()).unwrap()
+        }
+        // codes of the commands received, everything else is dropped
+        fn codes(&mut self) -> Vec<String> {
+            self.receive()
+                .into_iter()
+                .filter(|message| message.header.message_type_id == COMMAND_AMF0)
+                .map(|message| code(&Command::decode(&message.payload).unwrap()).to_string())
+                .collect()
+        }
+        // type id, timestamp and payload of the media forwarded by the
+        // next poll
+        fn media(&mut self) -> Vec<(u8, u32, Bytes)> {
+            self.session.poll(Instant::now()).unwrap();
+            self.receive()
+                .into_iter()
+                .map(|message| {
+                    assert_eq!(message.header.message_stream_id, self.stream_id);
+                    (
+                        message.header.message_type_id,
+                        message.header.timestamp,
+                        message.payload,
+                    )
+                })
+                .collect()
+        }
+    }
+
+    // Media sent by `publisher` on the "cam" stream.
+    fn on_media(publisher: &Client, message_type_id: u8, timestamp: u32, payload: &[u8]) {
+        let key = publisher.session.get_stream_key("cam").unwrap();
+        let message = MediaMessage {
+            message_type_id,
+            timestamp,
+            payload: Bytes::copy_from_slice(payload),
+        };
+        let config = publisher.session.get_config();
+        let mut registry = config.registry.lock().unwrap();
+        registry.on_media(&key, publisher.session.get_id(), message);
+    }
+
+    #[test]
+    fn decodes_amf3_command() {
+        let command = Command::new("createStream", 2.0, Amf0Value::Null, vec![]);
+        let mut payload = vec![0];
+        payload.extend(command.encode().unwrap());
+        assert_eq!(Command::decode_amf3(&payload).unwrap(), command);
+        assert!(Command::decode_amf3(&payload[1..]).is_err());
+    }
+
+    #[test]
+    fn connect_succeeds() {
+        let mut session = Session::new();
+        let string = |value: &str| Amf0Value::String(value.to_string());
+        let connect = Command::new(
+            "connect",
+            7.0,
+            object(vec![
+                ("app", string("live/")),
+                ("tcUrl", string("rtmp://localhost/live/")),
+                ("objectEncoding", Amf0Value::Number(3.0)),
+                ("type", string("nonprivate")),
+            ]),
+            vec![string("token")],
+        );
+        dispatch(&mut session, 0, &connect).unwrap();
+
+        let messages = ChunkDecoder::new().decode(session.take_output()).unwrap();
+        let message_type_ids: Vec<_> = messages
+            .iter()
+            .map(|message| message.header.message_type_id)
+            .collect();
+        assert_eq!(
+            message_type_ids,
+            [
+                WINDOW_ACKNOWLEDGEMENT_SIZE,
+                SET_PEER_BANDWIDTH,
+                SET_CHUNK_SIZE,
+                USER_CONTROL,
+                COMMAND_AMF0
+            ]
+        );
+        assert_eq!(
+            UserControlEvent::decode(&messages[3].payload).unwrap(),
+            UserControlEvent::StreamBegin(0)
+        );
+        let result = Command::decode(&messages[4].payload).unwrap();
+        assert_eq!(result.name, "_result");
+        assert_eq!(result.transaction_id, 7.0);
+        assert_eq!(
+            property(&result.command_object, "fmsVer").and_then(Amf0Value::try_as_str),
+            Some("FMS/3,5,7,7007")
+        );
+        assert_eq!(
+            property(&result.command_object, "capabilities"),
+            Some(&Amf0Value::Number(31.0))
+        );
+        assert_eq!(code(&result), "NetConnection.Connect.Success");
+        assert_eq!(
+            property(&result.arguments[0], "objectEncoding"),
+            Some(&Amf0Value::Number(3.0))
+        );
+
+        let params = session.get_connect_params().unwrap();
+        assert_eq!(params.app, "live");
+        assert_eq!(params.tc_url.as_deref(), Some("rtmp://localhost/live/"));
+        assert_eq!(params.object_encoding, 3.0);
+        assert_eq!(
+            params.properties,
+            [("type".to_string(), string("nonprivate"))]
+        );
+        assert_eq!(params.arguments, [string("token")]);
+    }
+
+    #[test]
+    fn reuses_lowest_free_stream_id() {
+        let mut session = Session::new();
+        let create_stream =
+            |transaction_id| Command::new("createStream", transaction_id, Amf0Value::Null, vec![]);
+        for transaction_id in [2.0, 3.0, 4.0] {
+            dispatch(&mut session, 0, &create_stream(transaction_id)).unwrap();
+        }
+        let delete_stream = Command::new(
+            "deleteStream",
+            0.0,
+            Amf0Value::Null,
+            vec![Amf0Value::Number(1.0)],
+        );
+        dispatch(&mut session, 0, &delete_stream).unwrap();
+        let close_stream = Command::new("closeStream", 0.0, Amf0Value::Null, vec![]);
+        dispatch(&mut session, 3, &close_stream).unwrap();
+        for transaction_id in [5.0, 6.0, 7.0] {
+            dispatch(&mut session, 0, &create_stream(transaction_id)).unwrap();
+        }
+
+        let results: Vec<_> = responses(&mut session)
+            .into_iter()
+            .map(|response| {
+                assert_eq!(response.name, "_result");
+                (response.transaction_id, response.arguments[0].clone())
+            })
+            .collect();
+        let expected: Vec<_> = [(2.0, 1), (3.0, 2), (4.0, 3), (5.0, 1), (6.0, 3), (7.0, 4)]
+            .into_iter()
+            .map(|(transaction_id, stream_id)| {
+                (transaction_id, Amf0Value::Number(stream_id as f64))
+            })
+            .collect();
+        assert_eq!(results, expected);
+        assert!(session.get_stream(2).is_some());
+    }
+
+    #[test]
+    fn malformed_command_gets_error() {
+        let mut session = Session::new();
+        let release_stream = Command::new("releaseStream", 2.0, Amf0Value::Null, vec![]);
+        dispatch(&mut session, 0, &release_stream).unwrap();
+        let connect = Command::new("connect", 1.0, object(vec![]), vec![]);
+        dispatch(&mut session, 0, &connect).unwrap();
+
+        let responses = responses(&mut session);
+        let codes: Vec<_> = responses
+            .iter()
+            .map(|response| {
+                (
+                    response.name.as_str(),
+                    response.transaction_id,
+                    code(response),
+                )
+            })
+            .collect();
+        assert_eq!(
+            codes,
+            [
+                ("_error", 2.0, "NetConnection.Call.Failed"),
+                ("_error", 1.0, "NetConnection.Connect.Rejected"),
+            ]
+        );
+    }
+
+    #[test]
+    fn stream_commands_before_connect_fail() {
+        let mut session = Session::new();
+        let stream_id = session.create_stream();
+        let publish = Command::new(
+            "publish",
+            0.0,
+            Amf0Value::Null,
+            vec![
+                Amf0Value::String("live".to_string()),
+                Amf0Value::String("live".to_string()),
+            ],
+        );
+        dispatch(&mut session, stream_id, &publish).unwrap();
+        let play = Command::new(
+            "play",
+            0.0,
+            Amf0Value::Null,
+            vec![Amf0Value::String("live".to_string())],
+        );
+        dispatch(&mut session, stream_id, &play).unwrap();
+
+        let responses = responses(&mut session);
+        let codes: Vec<_> = responses.iter().map(code).collect();
+        assert_eq!(
+            codes,
+            ["NetStream.Publish.BadName", "NetStream.Play.Failed"]
+        );
+    }
+
+    #[test]
+    fn second_publisher_gets_bad_name() {
+        let config = SessionConfig::default();
+        let mut first = Client::new(&config);
+        let mut second = Client::new(&config);
+        first.send("publish", &["cam", "live"]);
+        second.send("publish", &["cam?token=1", "live"]);
+        assert_eq!(first.codes(), ["NetStream.Publish.Start"]);
+        assert_eq!(second.codes(), ["NetStream.Publish.BadName"]);
+
+        // the name is free again once the first publisher stops
+        first.send("closeStream", &[]);
+        second.send("publish", &["cam", "live"]);
+        assert_eq!(second.codes(), ["NetStream.Publish.Start"]);
+    }
+
+    #[test]
+    fn players_get_rebased_media() {
+        let config = SessionConfig::default();
+        let mut publisher = Client::new(&config);
+        publisher.send("publish", &["cam", "live"]);
+        let mut players = [Client::new(&config), Client::new(&config)];
+        for player in &mut players {
+            player.send("play", &["cam"]);
+            assert_eq!(
+                player.codes(),
+                ["NetStream.Play.Reset", "NetStream.Play.Start"]
+            );
+        }
+
+        let keyframe = [0x17, 1, 0, 0, 0, 0xaa];
+        let audio = [0xaf, 1, 0xbb];
+        let inter_frame = [0x27, 1, 0, 0, 0, 0xcc];
+        on_media(&publisher, VIDEO, 5000, &keyframe);
+        on_media(&publisher, AUDIO, 5020, &audio);
+        on_media(&publisher, VIDEO, 5040, &inter_frame);
+        for player in &mut players {
+            assert_eq!(
+                player.media(),
+                [
+                    (VIDEO, 0, Bytes::copy_from_slice(&keyframe)),
+                    (AUDIO, 20, Bytes::copy_from_slice(&audio)),
+                    (VIDEO, 40, Bytes::copy_from_slice(&inter_frame)),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn late_player_gets_cached_messages_first() {
+        let config = SessionConfig::default();
+        let mut publisher = Client::new(&config);
+        publisher.send("publish", &["cam", "live"]);
+        let metadata = b"\x02\x00\x0aonMetaData\x05";
+        let video_sequence_header = [0x17, 0, 0, 0, 0, 1, 0x64];
+        let audio_sequence_header = [0xaf, 0, 0x12, 0x10];
+        let keyframe = [0x17, 1, 0, 0, 0, 0xaa];
+        let inter_frame = [0x27, 1, 0, 0, 0, 0xbb];
+        let live_frame = [0x27, 1, 0, 0, 0, 0xcc];
+        let mut set_data_frame = b"\x02\x00\x0d@setDataFrame".to_vec();
+        set_data_frame.extend(metadata);
+        on_media(&publisher, DATA_AMF0, 900, &set_data_frame);
+        on_media(&publisher, VIDEO, 900, &video_sequence_header);
+        on_media(&publisher, AUDIO, 900, &audio_sequence_header);
+        on_media(&publisher, VIDEO, 1000, &keyframe);
+        on_media(&publisher, VIDEO, 1040, &inter_frame);
+
+        let mut player = Client::new(&config);
+        player.send("play", &["cam"]);
+        player.codes();
+        on_media(&publisher, VIDEO, 1080, &live_frame);
+        let expected = [
+            (DATA_AMF0, 0, &metadata[..]),
+            (VIDEO, 0, &video_sequence_header),
+            (AUDIO, 0, &audio_sequence_header),
+            (VIDEO, 0, &keyframe),
+            (VIDEO, 40, &inter_frame),
+            (VIDEO, 80, &live_frame),
+        ]
+        .map(|(message_type_id, timestamp, payload)| {
+            (message_type_id, timestamp, Bytes::copy_from_slice(payload))
+        });
+        assert_eq!(player.media(), expected);
+    }
+}
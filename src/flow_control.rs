@@ -0,0 +1,219 @@
+use std::collections::HashSet;
+
+use crate::{
+    message::{RtmpMessage, is_audio_sequence_header},
+    video_tag::VideoTag,
+};
+
+// https://rtmp.veriskope.com/docs/spec/#545set-peer-bandwidth-6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitType {
+    Hard,
+    Soft,
+    Dynamic,
+}
+impl LimitType {
+    pub fn from_u8(limit_type: u8) -> Option<Self> {
+        match limit_type {
+            0 => Some(LimitType::Hard),
+            1 => Some(LimitType::Soft),
+            2 => Some(LimitType::Dynamic),
+            _ => None,
+        }
+    }
+}
+
+// Send side flow control: the peer limits our output with Set Peer Bandwidth
+// and tells how far it got with Acknowledgements, when more than the limit is
+// unacknowledged the peer isn't keeping up and media that can be skipped is
+// dropped instead of piling up. Players rarely set a limit, for them it's
+// derived from the Window Acknowledgement Size we sent.
+#[derive(Debug, Default)]
+pub struct FlowControl {
+    // every byte handed to the transport, handshake included, wraps around
+    bytes_sent: u32,
+    // sequence number of the peer's last Acknowledgement
+    bytes_acknowledged: u32,
+    // the peer sent an Acknowledgement, some never do and can't be measured
+    acknowledging: bool,
+    // window and limit type in effect, None until the peer sets one
+    limit: Option<(u32, LimitType)>,
+    // last Window Acknowledgement Size sent to the peer
+    window_acknowledgement_size: Option<u32>,
+    // message streams on which a video frame was dropped, the following ones
+    // can't be decoded until the next keyframe of that stream
+    waiting_for_keyframe: HashSet<u32>,
+    dropped: u64,
+}
+impl FlowControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn on_sent(&mut self, length: usize) {
+        self.bytes_sent = self.bytes_sent.wrapping_add(length as u32);
+    }
+    pub fn on_acknowledgement(&mut self, sequence_number: u32) {
+        self.bytes_acknowledged = sequence_number;
+        self.acknowledging = true;
+    }
+    pub fn on_window_acknowledgement_size_sent(&mut self, window_size: u32) {
+        self.window_acknowledgement_size = Some(window_size);
+    }
+    // Applies a Set Peer Bandwidth, returns the Window Acknowledgement Size to
+    // send back when it differs from the last one sent.
+    pub fn on_set_peer_bandwidth(
+        &mut self,
+        window_size: u32,
+        limit_type: LimitType,
+    ) -> Option<u32> {
+        let limit = match (limit_type, self.limit) {
+            (LimitType::Hard, _) => (window_size, LimitType::Hard),
+            (LimitType::Soft, Some((current, _))) => (window_size.min(current), LimitType::Soft),
+            (LimitType::Soft, None) => (window_size, LimitType::Soft),
+            (LimitType::Dynamic, Some((_, LimitType::Hard))) => (window_size, LimitType::Hard),
+            (LimitType::Dynamic, _) => return None,
+        };
+        self.limit = Some(limit);
+        if self.window_acknowledgement_size == Some(limit.0) {
+            return None;
+        }
+        self.window_acknowledgement_size = Some(limit.0);
+        Some(limit.0)
+    }
+    pub fn get_bytes_sent(&self) -> u32 {
+        self.bytes_sent
+    }
+    pub fn get_unacknowledged(&self) -> u32 {
+        self.bytes_sent.wrapping_sub(self.bytes_acknowledged)
+    }
+    pub fn get_limit(&self) -> Option<(u32, LimitType)> {
+        self.limit
+    }
+    pub fn get_dropped(&self) -> u64 {
+        self.dropped
+    }
+    // Unacknowledged bytes allowed before media is dropped. The peer only
+    // acknowledges once per window it received, so without a limit of its
+    // own a full window is always in flight and a second one is tolerated.
+    pub fn get_window(&self) -> Option<u32> {
+        match self.limit {
+            Some((window_size, _)) => Some(window_size),
+            None if self.acknowledging => self
+                .window_acknowledgement_size
+                .map(|window_size| window_size.saturating_mul(2)),
+            None => None,
+        }
+    }
+    pub fn is_congested(&self) -> bool {
+        self.get_window()
+            .is_some_and(|window_size| self.get_unacknowledged() > window_size)
+    }
+    // Whether a media message should be sent. Sequence headers and keyframes
+    // always are, other audio/video frames are dropped while congested, and
+    // once a video frame was dropped the rest of the GOP of that message
+    // stream goes with it.
+    pub fn admit(&mut self, message_stream_id: u32, message: &RtmpMessage) -> bool {
+        let admit = match message {
+            RtmpMessage::Video(payload) => match VideoTag::parse(payload) {
+                Ok(tag) if tag.is_sequence_header() => true,
+                Ok(tag) if tag.is_keyframe() => {
+                    self.waiting_for_keyframe.remove(&message_stream_id);
+                    true
+                }
+                _ => {
+                    if self.is_congested() {
+                        self.waiting_for_keyframe.insert(message_stream_id);
+                    }
+                    !self.waiting_for_keyframe.contains(&message_stream_id)
+                }
+            },
+            RtmpMessage::Audio(payload) if is_audio_sequence_header(payload) => true,
+            RtmpMessage::Audio(_) => !self.is_congested(),
+            _ => true,
+        };
+        if !admit {
+            self.dropped += 1;
+        }
+        admit
+    }
+    // The message stream was deleted, its id may be reused for another one.
+    pub fn remove_stream(&mut self, message_stream_id: u32) {
+        self.waiting_for_keyframe.remove(&message_stream_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    fn video(payload: &'static [u8]) -> RtmpMessage {
+        RtmpMessage::Video(Bytes::from_static(payload))
+    }
+
+    #[test]
+    fn uses_announced_window_without_peer_limit() {
+        let mut flow_control = FlowControl::new();
+        flow_control.on_window_acknowledgement_size_sent(1000);
+        flow_control.on_sent(5000);
+        // no Acknowledgement yet, nothing to measure against
+        assert!(!flow_control.is_congested());
+        flow_control.on_acknowledgement(3000);
+        assert!(!flow_control.is_congested());
+        flow_control.on_sent(1);
+        assert!(flow_control.is_congested());
+    }
+
+    #[test]
+    fn drops_inter_frames_until_keyframe_while_congested() {
+        let keyframe = video(b"\x17\x01\x00\x00\x00k");
+        let inter_frame = video(b"\x27\x01\x00\x00\x00p");
+        let mut flow_control = FlowControl::new();
+        flow_control.on_window_acknowledgement_size_sent(1000);
+        flow_control.on_acknowledgement(0);
+        assert!(flow_control.admit(1, &inter_frame));
+        flow_control.on_sent(2001);
+        assert!(!flow_control.admit(1, &inter_frame));
+        assert!(flow_control.admit(1, &video(b"\x17\x00\x00\x00\x00c")));
+        flow_control.on_acknowledgement(2001);
+        assert!(!flow_control.admit(1, &inter_frame));
+        assert!(flow_control.admit(1, &keyframe));
+        assert!(flow_control.admit(1, &inter_frame));
+        assert_eq!(flow_control.get_dropped(), 2);
+    }
+
+    #[test]
+    fn peer_limit_takes_precedence() {
+        let mut flow_control = FlowControl::new();
+        flow_control.on_window_acknowledgement_size_sent(1000);
+        assert_eq!(
+            flow_control.on_set_peer_bandwidth(500, LimitType::Hard),
+            Some(500)
+        );
+        assert_eq!(
+            flow_control.on_set_peer_bandwidth(800, LimitType::Soft),
+            None
+        );
+        flow_control.on_sent(501);
+        assert!(flow_control.is_congested());
+    }
+
+    #[test]
+    fn waits_for_keyframe_per_stream() {
+        let keyframe = video(b"\x17\x01\x00\x00\x00k");
+        let inter_frame = video(b"\x27\x01\x00\x00\x00p");
+        let mut flow_control = FlowControl::new();
+        flow_control.on_set_peer_bandwidth(1000, LimitType::Hard);
+        flow_control.on_sent(1001);
+        assert!(!flow_control.admit(1, &inter_frame));
+        assert!(!flow_control.admit(2, &inter_frame));
+        flow_control.on_acknowledgement(1001);
+        // a keyframe on stream 2 doesn't make stream 1 decodable
+        assert!(flow_control.admit(2, &keyframe));
+        assert!(flow_control.admit(2, &inter_frame));
+        assert!(!flow_control.admit(1, &inter_frame));
+        flow_control.remove_stream(1);
+        assert!(flow_control.admit(1, &inter_frame));
+    }
+}
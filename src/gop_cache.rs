@@ -0,0 +1,128 @@
+use std::collections::VecDeque;
+
+use crate::registry::MediaMessage;
+
+// Bounds of a GOP cache, a group of pictures going over any of them isn't
+// cached at all since it's useless without its keyframe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GopCacheConfig {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    // milliseconds between the keyframe and the last message
+    pub max_duration: u32,
+}
+impl Default for GopCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 1024,
+            max_bytes: 8 * 1024 * 1024,
+            max_duration: 10_000,
+        }
+    }
+}
+
+// Media messages since the last video keyframe, sent to a new player right
+// after the sequence headers so it can start rendering without waiting for
+// the next keyframe.
+#[derive(Debug)]
+pub struct GopCache {
+    config: GopCacheConfig,
+    messages: VecDeque<MediaMessage>,
+    bytes: usize,
+}
+impl GopCache {
+    pub fn new(config: GopCacheConfig) -> Self {
+        Self {
+            config,
+            messages: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+    pub fn get_messages(&self) -> impl Iterator<Item = &MediaMessage> {
+        self.messages.iter()
+    }
+    pub fn get_bytes(&self) -> usize {
+        self.bytes
+    }
+    pub fn clear(&mut self) {
+        self.messages.clear();
+        self.bytes = 0;
+    }
+    // Metadata and sequence headers are cached apart and must not be pushed.
+    pub fn push(&mut self, message: &MediaMessage) {
+        let keyframe = message
+            .get_video_tag()
+            .is_some_and(|tag| tag.is_keyframe() && !tag.is_sequence_header());
+        if keyframe {
+            self.clear();
+        } else if self.messages.is_empty() {
+            // nothing to decode it against
+            return;
+        }
+        self.messages.push_back(message.clone());
+        self.bytes += message.payload.len();
+
+        // audio is often interleaved a bit behind the video, a message older
+        // than the keyframe doesn't make the GOP any longer
+        let duration = match self.messages.front() {
+            Some(first) => (message.timestamp.wrapping_sub(first.timestamp) as i32).max(0) as u32,
+            None => 0,
+        };
+        if self.messages.len() > self.config.max_messages
+            || self.bytes > self.config.max_bytes
+            || duration > self.config.max_duration
+        {
+            self.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::message::{AUDIO, VIDEO};
+
+    fn message(message_type_id: u8, timestamp: u32, payload: &'static [u8]) -> MediaMessage {
+        MediaMessage {
+            message_type_id,
+            timestamp,
+            payload: Bytes::from_static(payload),
+        }
+    }
+
+    #[test]
+    fn keeps_gop_with_interleaved_audio() {
+        let mut cache = GopCache::new(GopCacheConfig::default());
+        cache.push(&message(VIDEO, 1000, b"\x17\x01\x00\x00\x00k"));
+        cache.push(&message(AUDIO, 990, b"\xaf\x01a"));
+        cache.push(&message(VIDEO, 1040, b"\x27\x01\x00\x00\x00p"));
+        let timestamps: Vec<u32> = cache.get_messages().map(|m| m.timestamp).collect();
+        assert_eq!(timestamps, [1000, 990, 1040]);
+    }
+
+    #[test]
+    fn skips_frames_before_first_keyframe() {
+        let mut cache = GopCache::new(GopCacheConfig::default());
+        cache.push(&message(VIDEO, 0, b"\x27\x01\x00\x00\x00p"));
+        cache.push(&message(AUDIO, 0, b"\xaf\x01a"));
+        assert_eq!(cache.get_messages().count(), 0);
+        cache.push(&message(VIDEO, 40, b"\x17\x01\x00\x00\x00k"));
+        assert_eq!(cache.get_messages().count(), 1);
+    }
+
+    #[test]
+    fn clears_gop_over_max_duration() {
+        let mut cache = GopCache::new(GopCacheConfig {
+            max_duration: 100,
+            ..GopCacheConfig::default()
+        });
+        cache.push(&message(VIDEO, 1000, b"\x17\x01\x00\x00\x00k"));
+        cache.push(&message(VIDEO, 1100, b"\x27\x01\x00\x00\x00p"));
+        assert_eq!(cache.get_messages().count(), 2);
+        cache.push(&message(VIDEO, 1101, b"\x27\x01\x00\x00\x00p"));
+        assert_eq!(cache.get_messages().count(), 0);
+        assert_eq!(cache.get_bytes(), 0);
+    }
+}
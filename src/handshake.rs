@@ -1,15 +1,62 @@
 use rand::Rng;
-use std::io::{self, Read, Write};
-use std::net::TcpStream;
+use std::{
+    error::Error,
+    fmt,
+    io::{self, Cursor, Read, Write},
+};
 
-use crate::utils::timestamp;
+use crate::{
+    handshake_digest::{
+        DIGEST_SIZE, DigestSchema, find_digest, genuine_fms_key, genuine_fp_key, response_digest,
+        write_digest,
+    },
+    utils::timestamp,
+};
 
-fn debug_print_bytes(label: &str, bytes: &[u8]) {
-    print!("{}: ", label);
-    for byte in bytes {
-        print!("{:02X} ", byte);
+// size of C1/C2/S1/S2
+pub const HANDSHAKE_SIZE: usize = 1536;
+
+// https://rtmp.veriskope.com/docs/spec/#521c0-and-s0-format
+pub const RTMP_VERSION: u8 = 3;
+// C0 version used by RTMPE, the encrypted variant
+pub const RTMPE_VERSION: u8 = 6;
+// Sent in the S1 version field of a digest handshake, clients expect a non-zero
+// FMS version there.
+const FMS_VERSION: [u8; 4] = [4, 5, 0, 1];
+// Sent in the C1 version field of a digest handshake.
+const FLASH_PLAYER_VERSION: [u8; 4] = [9, 0, 124, 2];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeError {
+    // RTMPE, recognized but not supported
+    Encrypted,
+    // anything else that isn't RTMP, e.g. 'G' from an HTTP request
+    UnsupportedVersion(u8),
+    // client side: S2 is neither an echo of C1 nor carries a valid digest
+    InvalidS2,
+}
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::Encrypted => write!(f, "encrypted RTMPE handshake is not supported"),
+            HandshakeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported RTMP version {}", version)
+            }
+            HandshakeError::InvalidS2 => write!(f, "S2 doesn't match C1"),
+        }
+    }
+}
+impl Error for HandshakeError {}
+impl From<HandshakeError> for io::Error {
+    fn from(error: HandshakeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+impl HandshakeError {
+    // The handshake error carried by `error`, if it is one.
+    pub fn from_io(error: &io::Error) -> Option<&HandshakeError> {
+        error.get_ref()?.downcast_ref()
     }
-    println!();
 }
 
 #[derive(Debug)]
@@ -21,7 +68,7 @@ impl HandshakeChunk0 {
         Self { version: [0u8; 1] }
     }
 
-    fn read(&mut self, stream: &mut TcpStream) -> io::Result<&mut Self> {
+    fn read<R: Read>(&mut self, stream: &mut R) -> io::Result<&mut Self> {
         stream.read_exact(&mut self.version)?;
         Ok(self)
     }
@@ -29,7 +76,7 @@ impl HandshakeChunk0 {
     fn set_version(&mut self, version: u8) {
         self.version = [version];
     }
-    fn write(&self, stream: &mut TcpStream) -> io::Result<()> {
+    fn write<W: Write>(&self, stream: &mut W) -> io::Result<()> {
         stream.write_all(&self.version)?;
         stream.flush()?;
         Ok(())
@@ -49,13 +96,13 @@ impl HandshakeChunk1 {
             random_data: [0u8; 1528],
         }
     }
-    fn read(&mut self, stream: &mut TcpStream) -> io::Result<&mut Self> {
+    fn read<R: Read>(&mut self, stream: &mut R) -> io::Result<&mut Self> {
         stream.read_exact(&mut self.time)?;
         stream.read_exact(&mut self.zero)?;
         stream.read_exact(&mut self.random_data)?;
         Ok(self)
     }
-    fn write(&self, stream: &mut TcpStream) -> io::Result<()> {
+    fn write<W: Write>(&self, stream: &mut W) -> io::Result<()> {
         stream.write_all(&self.time)?;
         stream.write_all(&self.zero)?;
         stream.write_all(&self.random_data)?;
@@ -80,6 +127,18 @@ impl HandshakeChunk1 {
         self.set_zero();
         self.set_random_data();
     }
+    fn to_bytes(&self) -> [u8; HANDSHAKE_SIZE] {
+        let mut bytes = Cursor::new([0u8; HANDSHAKE_SIZE]);
+        self.write(&mut bytes).unwrap();
+        bytes.into_inner()
+    }
+    fn set_bytes(&mut self, bytes: &[u8; HANDSHAKE_SIZE]) {
+        self.read(&mut &bytes[..]).unwrap();
+    }
+    // A zero version field means the peer only knows the simple handshake.
+    fn has_version(&self) -> bool {
+        self.zero != [0u8; 4]
+    }
 }
 #[derive(Debug)]
 struct HandshakeChunk2 {
@@ -95,13 +154,13 @@ impl HandshakeChunk2 {
             random_echo: [0u8; 1528],
         }
     }
-    fn read(&mut self, stream: &mut TcpStream) -> io::Result<&mut Self> {
+    fn read<R: Read>(&mut self, stream: &mut R) -> io::Result<&mut Self> {
         stream.read_exact(&mut self.time)?;
         stream.read_exact(&mut self.time_2)?;
         stream.read_exact(&mut self.random_echo)?;
         Ok(self)
     }
-    fn write(&self, stream: &mut TcpStream) -> io::Result<()> {
+    fn write<W: Write>(&self, stream: &mut W) -> io::Result<()> {
         stream.write_all(&self.time)?;
         stream.write_all(&self.time_2)?;
         stream.write_all(&self.random_echo)?;
@@ -117,10 +176,48 @@ impl HandshakeChunk2 {
     fn set_random_echo(&mut self, random_echo: [u8; 1528]) {
         self.random_echo = random_echo;
     }
+    fn to_bytes(&self) -> [u8; HANDSHAKE_SIZE] {
+        let mut bytes = Cursor::new([0u8; HANDSHAKE_SIZE]);
+        self.write(&mut bytes).unwrap();
+        bytes.into_inner()
+    }
+    fn set_bytes(&mut self, bytes: &[u8; HANDSHAKE_SIZE]) {
+        self.read(&mut &bytes[..]).unwrap();
+    }
 }
 
+// https://rtmp.veriskope.com/docs/spec/#523handshake-diagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeState {
+    Uninitialized,
+    VersionSent,
+    AckSent,
+    HandshakeDone,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeRole {
+    Server,
+    Client,
+}
+
+// Push based handshake: bytes from the peer go in through `push`, the chunks
+// to send back are collected in `take_output`.
+//
+// As server, a C1 carrying a valid Flash Player digest gets the digest
+// ("complex") handshake answer, anything else the simple one from the spec.
+// As client, C0/C1 are ready to be sent right away and the handshake follows
+// whichever variant the server answered S1 with.
 #[derive(Debug)]
-pub(crate) struct Handshake {
+pub struct Handshake {
+    role: HandshakeRole,
+    state: HandshakeState,
+    // digests of C1 and S1 when the digest handshake is used
+    c1_digest: Option<[u8; DIGEST_SIZE]>,
+    s1_digest: Option<[u8; DIGEST_SIZE]>,
+    // peer bytes of the handshake chunk(s) currently being received
+    buffer: Vec<u8>,
+    output: Vec<u8>,
     c0: HandshakeChunk0,
     c1: HandshakeChunk1,
     c2: HandshakeChunk2,
@@ -128,9 +225,20 @@ pub(crate) struct Handshake {
     s1: HandshakeChunk1,
     s2: HandshakeChunk2,
 }
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Handshake {
     pub fn new() -> Self {
         Self {
+            role: HandshakeRole::Server,
+            state: HandshakeState::Uninitialized,
+            c1_digest: None,
+            s1_digest: None,
+            buffer: Vec::with_capacity(HANDSHAKE_SIZE),
+            output: vec![],
             c0: HandshakeChunk0::new(),
             c1: HandshakeChunk1::new(),
             c2: HandshakeChunk2::new(),
@@ -139,23 +247,257 @@ impl Handshake {
             s2: HandshakeChunk2::new(),
         }
     }
-    pub fn response(&mut self, stream: &mut TcpStream) -> io::Result<()> {
-        self.c0.read(stream)?;
-        // TODO validate version!!!!!!
-        self.s0.set_version(3);
-        self.s0.write(stream)?;
+    // With `digest` C1 is signed so that servers requiring the digest handshake
+    // accept it, simple servers just echo it back either way.
+    pub fn new_client(digest: bool) -> Self {
+        let mut handshake = Self::new();
+        handshake.role = HandshakeRole::Client;
 
-        self.s1.prepare();
-        self.s1.write(stream)?;
+        handshake.c0.set_version(RTMP_VERSION);
+        handshake.c1.prepare();
+        if digest {
+            handshake.c1.zero = FLASH_PLAYER_VERSION;
+            let mut c1 = handshake.c1.to_bytes();
+            handshake.c1_digest = Some(write_digest(
+                &mut c1,
+                DigestSchema::Schema0,
+                &genuine_fp_key()[..30],
+            ));
+            handshake.c1.set_bytes(&c1);
+        }
+        // writing to a Vec can't fail
+        handshake.c0.write(&mut handshake.output).unwrap();
+        handshake.c1.write(&mut handshake.output).unwrap();
+        handshake.state = HandshakeState::VersionSent;
+        handshake
+    }
+    pub fn get_role(&self) -> HandshakeRole {
+        self.role
+    }
+    pub fn get_state(&self) -> HandshakeState {
+        self.state
+    }
+    pub fn is_complex(&self) -> bool {
+        self.s1_digest.is_some()
+    }
+    pub fn is_done(&self) -> bool {
+        self.state == HandshakeState::HandshakeDone
+    }
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+    // Consumes handshake bytes from `data` and returns how many were used, anything
+    // after C2 belongs to the chunk stream.
+    pub fn push(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut consumed = 0;
+        while !self.is_done() && consumed < data.len() {
+            let needed = match (self.role, self.state) {
+                (HandshakeRole::Server, HandshakeState::Uninitialized) => 1,
+                // S0 and S1
+                (HandshakeRole::Client, HandshakeState::VersionSent) => 1 + HANDSHAKE_SIZE,
+                _ => HANDSHAKE_SIZE,
+            };
+            let take = (needed - self.buffer.len()).min(data.len() - consumed);
+            self.buffer
+                .extend_from_slice(&data[consumed..consumed + take]);
+            consumed += take;
+            if self.buffer.len() == needed {
+                let buffer = std::mem::take(&mut self.buffer);
+                let mut stream = Cursor::new(buffer);
+                match self.role {
+                    HandshakeRole::Server => self.response(&mut stream)?,
+                    HandshakeRole::Client => self.request(&mut stream)?,
+                }
+            }
+        }
+        Ok(consumed)
+    }
+    fn response(&mut self, stream: &mut Cursor<Vec<u8>>) -> io::Result<()> {
+        match self.state {
+            HandshakeState::Uninitialized => {
+                self.c0.read(stream)?;
+                // nothing is sent back to a peer that doesn't speak RTMP
+                match self.c0.version[0] {
+                    RTMP_VERSION => {}
+                    RTMPE_VERSION => return Err(HandshakeError::Encrypted.into()),
+                    version => return Err(HandshakeError::UnsupportedVersion(version).into()),
+                }
+                self.s0.set_version(RTMP_VERSION);
+                self.s0.write(&mut self.output)?;
+                self.state = HandshakeState::VersionSent;
+            }
+            HandshakeState::VersionSent => {
+                // S1 can only be prepared once C1 tells which handshake the
+                // client speaks
+                self.c1.read(stream)?;
+                let c1 = self.c1.to_bytes();
+                let c1_digest = match self.c1.has_version() {
+                    true => find_digest(&c1, &genuine_fp_key()[..30]),
+                    false => None,
+                };
 
-        self.c1.read(stream)?;
+                self.s1.prepare();
+                match c1_digest {
+                    Some((schema, c1_digest)) => {
+                        println!("Digest handshake, {:?}", schema);
+                        self.c1_digest = Some(c1_digest);
+                        self.s1.zero = FMS_VERSION;
+                        let mut s1 = self.s1.to_bytes();
+                        self.s1_digest =
+                            Some(write_digest(&mut s1, schema, &genuine_fms_key()[..36]));
+                        self.s1.set_bytes(&s1);
+
+                        let mut s2 = [0u8; HANDSHAKE_SIZE];
+                        rand::rng().fill(&mut s2[..]);
+                        let digest = response_digest(&s2, &c1_digest, &genuine_fms_key());
+                        s2[HANDSHAKE_SIZE - DIGEST_SIZE..].copy_from_slice(&digest);
+                        self.s2.set_bytes(&s2);
+                    }
+                    None => {
+                        self.s2.set_time_2(0);
+                        self.s2.set_time(self.c1.time);
+                        self.s2.set_random_echo(self.c1.random_data);
+                    }
+                }
+                self.s1.write(&mut self.output)?;
+                self.s2.write(&mut self.output)?;
+                self.state = HandshakeState::AckSent;
+            }
+            HandshakeState::AckSent => {
+                self.c2.read(stream)?;
+                // Clients are known to get C2 wrong, a mismatch is only reported.
+                println!("C2 valid: {:?}", self.is_c2_valid());
+                self.state = HandshakeState::HandshakeDone;
+            }
+            HandshakeState::HandshakeDone => {}
+        }
+        Ok(())
+    }
+    // As server, whether the C2 received answers S1.
+    fn is_c2_valid(&self) -> bool {
+        match self.s1_digest {
+            Some(s1_digest) => {
+                let c2 = self.c2.to_bytes();
+                let digest = response_digest(&c2, &s1_digest, &genuine_fp_key());
+                c2[HANDSHAKE_SIZE - DIGEST_SIZE..] == digest
+            }
+            None => self.s1.random_data == self.c2.random_echo,
+        }
+    }
+    fn request(&mut self, stream: &mut Cursor<Vec<u8>>) -> io::Result<()> {
+        match self.state {
+            HandshakeState::VersionSent => {
+                self.s0.read(stream)?;
+                match self.s0.version[0] {
+                    RTMP_VERSION => {}
+                    RTMPE_VERSION => return Err(HandshakeError::Encrypted.into()),
+                    version => return Err(HandshakeError::UnsupportedVersion(version).into()),
+                }
+                self.s1.read(stream)?;
+                let s1 = self.s1.to_bytes();
+                self.s1_digest = match (self.c1_digest, self.s1.has_version()) {
+                    (Some(_), true) => {
+                        find_digest(&s1, &genuine_fms_key()[..36]).map(|(_, digest)| digest)
+                    }
+                    _ => None,
+                };
 
-        self.s2.set_time_2(0);
-        self.s2.set_time(self.c1.time);
-        self.s2.set_random_echo(self.c1.random_data);
-        self.s2.write(stream)?;
-        println!("{:?}", self.c1.random_data == self.s2.random_echo);
-        self.c2.read(stream)?;
+                match self.s1_digest {
+                    Some(s1_digest) => {
+                        let mut c2 = [0u8; HANDSHAKE_SIZE];
+                        rand::rng().fill(&mut c2[..]);
+                        let digest = response_digest(&c2, &s1_digest, &genuine_fp_key());
+                        c2[HANDSHAKE_SIZE - DIGEST_SIZE..].copy_from_slice(&digest);
+                        self.c2.set_bytes(&c2);
+                    }
+                    None => {
+                        self.c2.set_time(self.s1.time);
+                        self.c2.set_time_2(timestamp());
+                        self.c2.set_random_echo(self.s1.random_data);
+                    }
+                }
+                self.c2.write(&mut self.output)?;
+                self.state = HandshakeState::AckSent;
+            }
+            HandshakeState::AckSent => {
+                self.s2.read(stream)?;
+                let valid = match (self.s1_digest, self.c1_digest) {
+                    (Some(_), Some(c1_digest)) => {
+                        let s2 = self.s2.to_bytes();
+                        let digest = response_digest(&s2, &c1_digest, &genuine_fms_key());
+                        s2[HANDSHAKE_SIZE - DIGEST_SIZE..] == digest
+                    }
+                    _ => self.c1.random_data == self.s2.random_echo,
+                };
+                if !valid {
+                    return Err(HandshakeError::InvalidS2.into());
+                }
+                self.state = HandshakeState::HandshakeDone;
+            }
+            _ => {}
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs a client and a server handshake against each other in memory.
+    fn loopback(digest: bool) -> (Handshake, Handshake) {
+        let mut client = Handshake::new_client(digest);
+        let mut server = Handshake::new();
+        while !client.is_done() || !server.is_done() {
+            let to_server = client.take_output();
+            let to_client = server.take_output();
+            assert!(
+                !to_server.is_empty() || !to_client.is_empty(),
+                "handshake stalled"
+            );
+            assert_eq!(server.push(&to_server).unwrap(), to_server.len());
+            assert_eq!(client.push(&to_client).unwrap(), to_client.len());
+        }
+        (client, server)
+    }
+
+    #[test]
+    fn simple_handshake_loopback() {
+        let (client, server) = loopback(false);
+        assert!(!client.is_complex());
+        assert!(!server.is_complex());
+        assert!(server.is_c2_valid());
+    }
+
+    #[test]
+    fn digest_handshake_loopback() {
+        let (client, server) = loopback(true);
+        assert!(client.is_complex());
+        assert!(server.is_complex());
+        assert!(server.is_c2_valid());
+    }
+
+    #[test]
+    fn leaves_bytes_after_c2() {
+        let mut client = Handshake::new_client(false);
+        let mut server = Handshake::new();
+        // one byte at a time, as slow as a peer can get
+        for byte in client.take_output() {
+            assert_eq!(server.push(&[byte]).unwrap(), 1);
+        }
+        client.push(&server.take_output()).unwrap();
+        let mut data = client.take_output();
+        data.extend(b"connect");
+        assert_eq!(server.push(&data).unwrap(), HANDSHAKE_SIZE);
+        assert!(server.is_done());
+    }
+
+    #[test]
+    fn rejects_encrypted_handshake() {
+        let error = Handshake::new().push(&[RTMPE_VERSION]).unwrap_err();
+        assert!(matches!(
+            HandshakeError::from_io(&error),
+            Some(HandshakeError::Encrypted)
+        ));
+    }
+}
@@ -0,0 +1,109 @@
+// Digest ("complex") handshake used by Flash Player and FMS. C1/S1 carry an
+// HMAC-SHA256 digest of the packet at an offset derived from the packet itself,
+// and C2/S2 end with a digest keyed off the peer's C1/S1 digest.
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::handshake::HANDSHAKE_SIZE;
+
+pub const DIGEST_SIZE: usize = 32;
+
+const GENUINE_KEY_TAIL: [u8; 32] = [
+    0xF0, 0xEE, 0xC2, 0x4A, 0x80, 0x68, 0xBE, 0xE8, 0x2E, 0x00, 0xD0, 0xD1, 0x02, 0x9E, 0x7E, 0x57,
+    0x6E, 0xEC, 0x5D, 0x2D, 0x29, 0x80, 0x6F, 0xAB, 0x93, 0xB8, 0xE6, 0x36, 0xCF, 0xEB, 0x31, 0xAE,
+];
+const GENUINE_FMS: &[u8; 36] = b"Genuine Adobe Flash Media Server 001";
+const GENUINE_FP: &[u8; 30] = b"Genuine Adobe Flash Player 001";
+
+// The first 36 bytes sign S1, all 68 bytes derive the key of the C2/S2 digest.
+pub fn genuine_fms_key() -> [u8; 68] {
+    let mut key = [0u8; 68];
+    key[..36].copy_from_slice(GENUINE_FMS);
+    key[36..].copy_from_slice(&GENUINE_KEY_TAIL);
+    key
+}
+// The first 30 bytes sign C1, all 62 bytes derive the key of the C2/S2 digest.
+pub fn genuine_fp_key() -> [u8; 62] {
+    let mut key = [0u8; 62];
+    key[..30].copy_from_slice(GENUINE_FP);
+    key[30..].copy_from_slice(&GENUINE_KEY_TAIL);
+    key
+}
+
+// Where the 764 byte digest block sits in C1/S1, after the time and version:
+// schema 0 puts the key block first, schema 1 the digest block first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DigestSchema {
+    Schema0,
+    Schema1,
+}
+impl DigestSchema {
+    fn digest_block(self) -> usize {
+        match self {
+            DigestSchema::Schema0 => 8 + 764,
+            DigestSchema::Schema1 => 8,
+        }
+    }
+}
+
+pub fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+// The digest block starts with 4 bytes whose sum gives the digest position.
+pub fn digest_offset(packet: &[u8; HANDSHAKE_SIZE], schema: DigestSchema) -> usize {
+    let block = schema.digest_block();
+    let sum: usize = packet[block..block + 4].iter().map(|b| *b as usize).sum();
+    block + 4 + sum % 728
+}
+
+// HMAC of the whole packet except the digest itself.
+pub fn compute_digest(
+    packet: &[u8; HANDSHAKE_SIZE],
+    schema: DigestSchema,
+    key: &[u8],
+) -> [u8; DIGEST_SIZE] {
+    let offset = digest_offset(packet, schema);
+    hmac_sha256(key, &[&packet[..offset], &packet[offset + DIGEST_SIZE..]])
+}
+
+// Looks for a valid C1/S1 digest under either schema.
+pub fn find_digest(
+    packet: &[u8; HANDSHAKE_SIZE],
+    key: &[u8],
+) -> Option<(DigestSchema, [u8; DIGEST_SIZE])> {
+    [DigestSchema::Schema0, DigestSchema::Schema1]
+        .into_iter()
+        .find_map(|schema| {
+            let offset = digest_offset(packet, schema);
+            let digest = compute_digest(packet, schema, key);
+            (packet[offset..offset + DIGEST_SIZE] == digest).then_some((schema, digest))
+        })
+}
+
+// Signs a C1/S1 in place and returns its digest.
+pub fn write_digest(
+    packet: &mut [u8; HANDSHAKE_SIZE],
+    schema: DigestSchema,
+    key: &[u8],
+) -> [u8; DIGEST_SIZE] {
+    let offset = digest_offset(packet, schema);
+    let digest = compute_digest(packet, schema, key);
+    packet[offset..offset + DIGEST_SIZE].copy_from_slice(&digest);
+    digest
+}
+
+// Digest closing a C2/S2: HMAC of everything before it, keyed by the HMAC of
+// the peer's C1/S1 digest.
+pub fn response_digest(
+    packet: &[u8; HANDSHAKE_SIZE],
+    peer_digest: &[u8; DIGEST_SIZE],
+    key: &[u8],
+) -> [u8; DIGEST_SIZE] {
+    let response_key = hmac_sha256(key, &[peer_digest]);
+    hmac_sha256(&response_key, &[&packet[..HANDSHAKE_SIZE - DIGEST_SIZE]])
+}
@@ -0,0 +1,26 @@
+//! RTMP protocol implementation: chunk stream encoding/decoding, handshake,
+//! protocol messages and the per connection session, plus a ready to use server.
+pub mod basic_header;
+pub mod chunk;
+pub mod chunk_decoder;
+pub mod chunk_writer;
+pub mod command;
+pub mod flow_control;
+pub mod gop_cache;
+pub mod handshake;
+pub mod handshake_digest;
+pub mod message;
+pub mod message_header;
+pub mod net_stream;
+pub mod registry;
+pub mod server;
+pub mod session;
+pub mod subscription;
+pub mod video_tag;
+mod utils;
+
+pub use chunk_decoder::{ChunkDecoder, RawMessage};
+pub use chunk_writer::ChunkWriter;
+pub use handshake::Handshake;
+pub use message::RtmpMessage;
+pub use session::Session;
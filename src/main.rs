@@ -1,28 +1,18 @@
-mod basic_header;
-mod chunk;
-mod handshake;
-mod message_header;
-mod session;
-mod utils;
-mod message;
-use std::{net::TcpListener, thread};
+use rtmp::{server, session::SessionConfig};
 
-use session::Session;
-fn main() {
-    let listener = TcpListener::bind("127.0.0.1:1935").unwrap();
-    println!("RTMP server listening on port 1935");
+const ADDR: &str = "127.0.0.1:1935";
+
+#[cfg(feature = "tokio")]
+#[tokio::main]
+async fn main() {
+    if let Err(e) = server::serve(ADDR, SessionConfig::default()).await {
+        eprintln!("Error: {}", e);
+    }
+}
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(mut s) => {
-                thread::spawn(move || {
-                    let mut session = Session::new(&mut s);
-                    session.handle()
-                });
-            }
-            Err(e) => {
-                eprintln!("Error: {}", e);
-            }
-        }
+#[cfg(not(feature = "tokio"))]
+fn main() {
+    if let Err(e) = server::serve_blocking(ADDR, SessionConfig::default()) {
+        eprintln!("Error: {}", e);
     }
-}
\ No newline at end of file
+}
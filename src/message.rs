@@ -1,21 +1,67 @@
 use byteorder::{BigEndian, WriteBytesExt};
-use std::{
-    io::{Cursor, Read, Write},
-    net::TcpStream,
-    time::Duration,
-};
-
-use amf::{Amf0Value, Value, Version};
+use bytes::Bytes;
+use std::io::{self, Cursor};
 
 use crate::{
-    basic_header::BasicHeader1,
-    message_header::{MessageHeaderType0, MessageHeaderType1},
+    chunk::MAX_CHUNK_SIZE, chunk_writer::ChunkWriter, command::Command, flow_control::LimitType,
+    session::Session,
 };
 
+// Protocol control messages go on chunk stream 2 with message stream id 0.
+// https://rtmp.veriskope.com/docs/spec/#54protocol-control-messages
+pub const PROTOCOL_CONTROL_CS_ID: u32 = 2;
+pub const COMMAND_CS_ID: u32 = 3;
+// media gets its own chunk streams so audio, video and data headers compress
+// independently of each other
+pub const DATA_CS_ID: u32 = 5;
+pub const AUDIO_CS_ID: u32 = 6;
+pub const VIDEO_CS_ID: u32 = 7;
+
+// message type ids
+pub const SET_CHUNK_SIZE: u8 = 1;
+pub const ABORT: u8 = 2;
+pub const ACKNOWLEDGEMENT: u8 = 3;
+pub const USER_CONTROL: u8 = 4;
+pub const WINDOW_ACKNOWLEDGEMENT_SIZE: u8 = 5;
+pub const SET_PEER_BANDWIDTH: u8 = 6;
+pub const AUDIO: u8 = 8;
+pub const VIDEO: u8 = 9;
+pub const DATA_AMF3: u8 = 15;
+pub const SHARED_OBJECT_AMF3: u8 = 16;
+pub const COMMAND_AMF3: u8 = 17;
+pub const DATA_AMF0: u8 = 18;
+pub const SHARED_OBJECT_AMF0: u8 = 19;
+pub const COMMAND_AMF0: u8 = 20;
+pub const AGGREGATE: u8 = 22;
+
+// Audio payloads are FLV tag bodies, the first byte tells the codec, AAC then
+// has a packet type byte where 0 marks the sequence header
+// (AudioSpecificConfig), see the audio tags section of the FLV file format
+// spec. Video tags are parsed by `VideoTag`.
+pub fn is_audio_sequence_header(payload: &[u8]) -> bool {
+    // AAC
+    matches!(payload, [b, 0, ..] if b >> 4 == 10)
+}
+
+fn fixed_payload<const N: usize>(message_type_id: u8, payload: &[u8]) -> io::Result<[u8; N]> {
+    payload.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "message type {} has a {} byte payload, expected {}",
+                message_type_id,
+                payload.len(),
+                N
+            ),
+        )
+    })
+}
+
 pub trait Message {
-    fn handle(&self, stream: &mut TcpStream);
+    fn handle(&self, session: &mut Session) -> io::Result<()>;
 }
 // =====================================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SetChunkSize {
     payload: [u8; 4], // chunk_size
 }
@@ -23,15 +69,94 @@ impl SetChunkSize {
     pub fn new(payload: [u8; 4]) -> Self {
         Self { payload }
     }
+    pub fn new_from_values(chunk_size: u32) -> Self {
+        Self {
+            payload: u32::to_be_bytes(chunk_size & 0x7FFFFFFF),
+        }
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn get_chunk_size(&self) -> u32 {
+        // the first bit must be zero
+        u32::from_be_bytes(self.payload) & 0x7FFFFFFF
+    }
+    // The chunk size to apply, sizes above MAX_CHUNK_SIZE are all equivalent
+    // since no chunk can be larger than a message.
+    pub fn validate(&self) -> io::Result<u32> {
+        let chunk_size = u32::from_be_bytes(self.payload);
+        if chunk_size & 0x80000000 != 0 || chunk_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid chunk size {:#x}", chunk_size),
+            ));
+        }
+        Ok(chunk_size.min(MAX_CHUNK_SIZE))
+    }
 }
 impl Message for SetChunkSize {
-    fn handle(&self, stream: &mut TcpStream) {
+    fn handle(&self, _session: &mut Session) -> io::Result<()> {
         println!("handle SetChunkSize");
-        println!("chunk sizeset to: {}", u32::from_be_bytes(self.payload))
+        // already applied by the chunk decoder before parsing the next chunk
+        println!("chunk sizeset to: {}", self.get_chunk_size());
+        Ok(())
+    }
+}
+
+// =====================================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AbortMessage {
+    payload: [u8; 4], // chunk stream id
+}
+impl AbortMessage {
+    pub fn new(payload: [u8; 4]) -> Self {
+        Self { payload }
+    }
+    pub fn new_from_values(cs_id: u32) -> Self {
+        Self {
+            payload: u32::to_be_bytes(cs_id),
+        }
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn get_cs_id(&self) -> u32 {
+        u32::from_be_bytes(self.payload)
     }
 }
 
 // =====================================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acknowledgement {
+    payload: [u8; 4], // sequence number, bytes received so far
+}
+impl Acknowledgement {
+    pub fn new(payload: [u8; 4]) -> Self {
+        Self { payload }
+    }
+    pub fn new_from_values(sequence_number: u32) -> Self {
+        Self {
+            payload: u32::to_be_bytes(sequence_number),
+        }
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn get_sequence_number(&self) -> u32 {
+        u32::from_be_bytes(self.payload)
+    }
+}
+impl Message for Acknowledgement {
+    fn handle(&self, session: &mut Session) -> io::Result<()> {
+        session
+            .get_flow_control()
+            .on_acknowledgement(self.get_sequence_number());
+        Ok(())
+    }
+}
+
+// =====================================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct AcknowledgementWindowSize {
     payload: [u8; 4],
 }
@@ -39,13 +164,26 @@ impl AcknowledgementWindowSize {
     pub fn new(payload: [u8; 4]) -> Self {
         Self { payload }
     }
+    pub fn new_from_values(window_size: u32) -> Self {
+        Self {
+            payload: u32::to_be_bytes(window_size),
+        }
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn get_window_size(&self) -> u32 {
+        u32::from_be_bytes(self.payload)
+    }
 }
 impl Message for AcknowledgementWindowSize {
-    fn handle(&self, stream: &mut TcpStream) {
-        todo!()
+    fn handle(&self, session: &mut Session) -> io::Result<()> {
+        session.set_window_acknowledgement_size(self.get_window_size());
+        Ok(())
     }
 }
 // =====================================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SetPeerBandwidth {
     payload: [u8; 5],
 }
@@ -57,19 +195,168 @@ impl SetPeerBandwidth {
         let mut data = Cursor::new([0u8; 5]);
         data.write_u32::<BigEndian>(ack_win_size).unwrap();
         data.write_u8(limit_type).unwrap();
-        Self { payload: data.into_inner() }
+        Self {
+            payload: data.into_inner(),
+        }
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn get_window_size(&self) -> u32 {
+        u32::from_be_bytes(self.payload[..4].try_into().unwrap())
+    }
+    // 0 hard, 1 soft, 2 dynamic
+    pub fn get_limit_type(&self) -> u8 {
+        self.payload[4]
+    }
+}
+impl Message for SetPeerBandwidth {
+    fn handle(&self, session: &mut Session) -> io::Result<()> {
+        let Some(limit_type) = LimitType::from_u8(self.get_limit_type()) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "invalid Set Peer Bandwidth limit type {}",
+                    self.get_limit_type()
+                ),
+            ));
+        };
+        // a changed window has to be acknowledged with a Window Acknowledgement Size
+        let flow_control = session.get_flow_control();
+        if let Some(window_size) =
+            flow_control.on_set_peer_bandwidth(self.get_window_size(), limit_type)
+        {
+            RtmpMessage::WindowAcknowledgementSize(AcknowledgementWindowSize::new_from_values(
+                window_size,
+            ))
+            .write(session.get_writer(), PROTOCOL_CONTROL_CS_ID, 0, 0)?;
+        }
+        Ok(())
     }
 }
 // =====================================================================================
-struct UserControlMessage {
+// https://rtmp.veriskope.com/docs/spec/#7114user-control-message-events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserControlEvent {
+    // stream id
+    StreamBegin(u32),
+    StreamEof(u32),
+    StreamDry(u32),
+    // buffer length in milliseconds
+    SetBufferLength { stream_id: u32, buffer_length: u32 },
+    StreamIsRecorded(u32),
+    // server timestamp in milliseconds, echoed back in the response
+    PingRequest(u32),
+    PingResponse(u32),
+    Unknown { event_type: u16, data: Vec<u8> },
+}
+impl UserControlEvent {
+    pub fn get_event_type(&self) -> u16 {
+        match self {
+            UserControlEvent::StreamBegin(_) => 0,
+            UserControlEvent::StreamEof(_) => 1,
+            UserControlEvent::StreamDry(_) => 2,
+            UserControlEvent::SetBufferLength { .. } => 3,
+            UserControlEvent::StreamIsRecorded(_) => 4,
+            UserControlEvent::PingRequest(_) => 6,
+            UserControlEvent::PingResponse(_) => 7,
+            UserControlEvent::Unknown { event_type, .. } => *event_type,
+        }
+    }
+    // Event type followed by the event data.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = u16::to_be_bytes(self.get_event_type()).to_vec();
+        match self {
+            UserControlEvent::StreamBegin(value)
+            | UserControlEvent::StreamEof(value)
+            | UserControlEvent::StreamDry(value)
+            | UserControlEvent::StreamIsRecorded(value)
+            | UserControlEvent::PingRequest(value)
+            | UserControlEvent::PingResponse(value) => payload.extend(u32::to_be_bytes(*value)),
+            UserControlEvent::SetBufferLength {
+                stream_id,
+                buffer_length,
+            } => {
+                payload.extend(u32::to_be_bytes(*stream_id));
+                payload.extend(u32::to_be_bytes(*buffer_length));
+            }
+            UserControlEvent::Unknown { data, .. } => payload.extend(data),
+        }
+        payload
+    }
+    pub fn decode(payload: &[u8]) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid User Control Message payload {:02x?}", payload),
+            )
+        };
+        let [a, b, data @ ..] = payload else {
+            return Err(invalid());
+        };
+        let event_type = u16::from_be_bytes([*a, *b]);
+        let value = |index: usize| {
+            data.get(index * 4..index * 4 + 4)
+                .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+                .ok_or_else(invalid)
+        };
+        Ok(match event_type {
+            0 => UserControlEvent::StreamBegin(value(0)?),
+            1 => UserControlEvent::StreamEof(value(0)?),
+            2 => UserControlEvent::StreamDry(value(0)?),
+            3 => UserControlEvent::SetBufferLength {
+                stream_id: value(0)?,
+                buffer_length: value(1)?,
+            },
+            4 => UserControlEvent::StreamIsRecorded(value(0)?),
+            6 => UserControlEvent::PingRequest(value(0)?),
+            7 => UserControlEvent::PingResponse(value(0)?),
+            _ => UserControlEvent::Unknown {
+                event_type,
+                data: data.to_vec(),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserControlMessage {
     payload: Vec<u8>,
 }
 impl UserControlMessage {
     pub fn new(payload: Vec<u8>) -> Self {
         Self { payload }
     }
+    pub fn new_from_event(event: &UserControlEvent) -> Self {
+        Self {
+            payload: event.encode(),
+        }
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn get_event(&self) -> io::Result<UserControlEvent> {
+        UserControlEvent::decode(&self.payload)
+    }
+}
+impl Message for UserControlMessage {
+    fn handle(&self, session: &mut Session) -> io::Result<()> {
+        match self.get_event()? {
+            UserControlEvent::SetBufferLength {
+                stream_id,
+                buffer_length,
+            } => session.set_buffer_length(stream_id, buffer_length),
+            UserControlEvent::PingRequest(timestamp) => {
+                session.send_user_control(&UserControlEvent::PingResponse(timestamp))?
+            }
+            UserControlEvent::PingResponse(timestamp) => session.on_ping_response(timestamp),
+            event => println!("User Control event: {:?}", event),
+        }
+        Ok(())
+    }
 }
 // =====================================================================================
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct CommandMessageAmf0 {
     payload: Vec<u8>,
 }
@@ -77,162 +364,245 @@ impl CommandMessageAmf0 {
     pub fn new(data: Vec<u8>) -> Self {
         Self { payload: data }
     }
+    pub fn new_from_command(command: &Command) -> io::Result<Self> {
+        Ok(Self {
+            payload: command.encode()?,
+        })
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        &self.payload
+    }
+    pub fn get_command(&self) -> io::Result<Command> {
+        Command::decode(&self.payload)
+    }
 }
-impl Message for CommandMessageAmf0 {
-    // +--------------+                              +-------------+
-    // |    Client    |             |                |    Server   |
-    // +------+-------+             |                +------+------+
-    //        |              Handshaking done               |
-    //        |                     |                       |
-    //        |                     |                       |
-    //        |                     |                       |
-    //        |                     |                       |
-    //        |----------- Command Message(connect) ------->|
-    //        |                                             |
-    //        |<------- Window Acknowledgement Size --------|
-    //        |                                             |
-    //        |<----------- Set Peer Bandwidth -------------|
-    //        |                                             |
-    //        |-------- Window Acknowledgement Size ------->|
-    //        |                                             |
-    //        |<------ User Control Message(StreamBegin) ---|
-    //        |                                             |
-    //        |<------------ Command Message ---------------|
-    //        |       (_result- connect response)           |
-    //        |                                             |
-
-    fn handle(&self, stream: &mut TcpStream) {
-        println!("CommandMessageAmf0");
-
-        let mut decoder = amf::amf0::Decoder::new(&self.payload[..]);
-        let command = decoder.decode();
-        let transaction_id = decoder.decode();
-        let command_object = decoder.decode();
-        println!("{:?}", command);
-        println!("{:?}", transaction_id);
-        println!("{:?}", command_object);
-
-        //acknowledgement_window_size message chunk
-        let basic_header = BasicHeader1::new_from_values(0, 2);
-        let message_header = MessageHeaderType0::new_from_values(0, 4, 5, 0);
-        let acknowledgement_window_size = AcknowledgementWindowSize::new(u32::to_be_bytes(5000000));
-
-        match stream.write_all(&basic_header.data) {
-            Ok(_) => println!("Basic header sent successfully."),
-            Err(e) => println!("Failed to send basic header: {:?}", e),
-        };
-        match stream.write_all(&message_header.data) {
-            Ok(_) => println!("Message header sent successfully."),
-            Err(e) => println!("Failed to send message header: {:?}", e),
-        };
-        match stream.write_all(&acknowledgement_window_size.payload) {
-            Ok(_) => println!("acknowledgement_window_size sent successfully."),
-            Err(e) => println!("Failed to send acknowledgement_window_size: {:?}", e),
-        };
-        match stream.flush() {
-            Ok(_) => println!("flushed successfully."),
-            Err(e) => println!("Failed to flush: {:?}", e),
+
+// =====================================================================================
+// Every message type of the spec, the payload of the ones this crate doesn't
+// interpret (media, data, shared objects...) is kept as is so that
+// `decode` followed by `encode` gives back the exact same bytes.
+// https://rtmp.veriskope.com/docs/spec/#54protocol-control-messages
+// https://rtmp.veriskope.com/docs/spec/#71types-of-messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RtmpMessage {
+    SetChunkSize(SetChunkSize),
+    Abort(AbortMessage),
+    Acknowledgement(Acknowledgement),
+    UserControl(UserControlMessage),
+    WindowAcknowledgementSize(AcknowledgementWindowSize),
+    SetPeerBandwidth(SetPeerBandwidth),
+    Audio(Bytes),
+    Video(Bytes),
+    DataAmf3(Bytes),
+    SharedObjectAmf3(Bytes),
+    CommandAmf3(Bytes),
+    DataAmf0(Bytes),
+    SharedObjectAmf0(Bytes),
+    CommandAmf0(CommandMessageAmf0),
+    Aggregate(Bytes),
+    Unknown { message_type_id: u8, payload: Bytes },
+}
+impl RtmpMessage {
+    pub fn decode(message_type_id: u8, payload: &[u8]) -> io::Result<Self> {
+        Self::decode_bytes(message_type_id, Bytes::copy_from_slice(payload))
+    }
+    // Same as `decode`, without copying the payload of media and data messages.
+    pub fn decode_bytes(message_type_id: u8, payload: Bytes) -> io::Result<Self> {
+        Ok(match message_type_id {
+            SET_CHUNK_SIZE => RtmpMessage::SetChunkSize(SetChunkSize::new(fixed_payload(
+                message_type_id,
+                &payload,
+            )?)),
+            ABORT => {
+                RtmpMessage::Abort(AbortMessage::new(fixed_payload(message_type_id, &payload)?))
+            }
+            ACKNOWLEDGEMENT => RtmpMessage::Acknowledgement(Acknowledgement::new(fixed_payload(
+                message_type_id,
+                &payload,
+            )?)),
+            USER_CONTROL => RtmpMessage::UserControl(UserControlMessage::new(payload.to_vec())),
+            WINDOW_ACKNOWLEDGEMENT_SIZE => RtmpMessage::WindowAcknowledgementSize(
+                AcknowledgementWindowSize::new(fixed_payload(message_type_id, &payload)?),
+            ),
+            SET_PEER_BANDWIDTH => RtmpMessage::SetPeerBandwidth(SetPeerBandwidth::new(
+                fixed_payload(message_type_id, &payload)?,
+            )),
+            AUDIO => RtmpMessage::Audio(payload),
+            VIDEO => RtmpMessage::Video(payload),
+            DATA_AMF3 => RtmpMessage::DataAmf3(payload),
+            SHARED_OBJECT_AMF3 => RtmpMessage::SharedObjectAmf3(payload),
+            COMMAND_AMF3 => RtmpMessage::CommandAmf3(payload),
+            DATA_AMF0 => RtmpMessage::DataAmf0(payload),
+            SHARED_OBJECT_AMF0 => RtmpMessage::SharedObjectAmf0(payload),
+            COMMAND_AMF0 => RtmpMessage::CommandAmf0(CommandMessageAmf0::new(payload.to_vec())),
+            AGGREGATE => RtmpMessage::Aggregate(payload),
+            _ => RtmpMessage::Unknown {
+                message_type_id,
+                payload,
+            },
+        })
+    }
+    pub fn get_message_type_id(&self) -> u8 {
+        match self {
+            RtmpMessage::SetChunkSize(_) => SET_CHUNK_SIZE,
+            RtmpMessage::Abort(_) => ABORT,
+            RtmpMessage::Acknowledgement(_) => ACKNOWLEDGEMENT,
+            RtmpMessage::UserControl(_) => USER_CONTROL,
+            RtmpMessage::WindowAcknowledgementSize(_) => WINDOW_ACKNOWLEDGEMENT_SIZE,
+            RtmpMessage::SetPeerBandwidth(_) => SET_PEER_BANDWIDTH,
+            RtmpMessage::Audio(_) => AUDIO,
+            RtmpMessage::Video(_) => VIDEO,
+            RtmpMessage::DataAmf3(_) => DATA_AMF3,
+            RtmpMessage::SharedObjectAmf3(_) => SHARED_OBJECT_AMF3,
+            RtmpMessage::CommandAmf3(_) => COMMAND_AMF3,
+            RtmpMessage::DataAmf0(_) => DATA_AMF0,
+            RtmpMessage::SharedObjectAmf0(_) => SHARED_OBJECT_AMF0,
+            RtmpMessage::CommandAmf0(_) => COMMAND_AMF0,
+            RtmpMessage::Aggregate(_) => AGGREGATE,
+            RtmpMessage::Unknown {
+                message_type_id, ..
+            } => *message_type_id,
         }
+    }
+    pub fn get_payload(&self) -> &[u8] {
+        match self {
+            RtmpMessage::SetChunkSize(message) => message.get_payload(),
+            RtmpMessage::Abort(message) => message.get_payload(),
+            RtmpMessage::Acknowledgement(message) => message.get_payload(),
+            RtmpMessage::UserControl(message) => message.get_payload(),
+            RtmpMessage::WindowAcknowledgementSize(message) => message.get_payload(),
+            RtmpMessage::SetPeerBandwidth(message) => message.get_payload(),
+            RtmpMessage::CommandAmf0(message) => message.get_payload(),
+            RtmpMessage::Audio(payload)
+            | RtmpMessage::Video(payload)
+            | RtmpMessage::DataAmf3(payload)
+            | RtmpMessage::SharedObjectAmf3(payload)
+            | RtmpMessage::CommandAmf3(payload)
+            | RtmpMessage::DataAmf0(payload)
+            | RtmpMessage::SharedObjectAmf0(payload)
+            | RtmpMessage::Aggregate(payload)
+            | RtmpMessage::Unknown { payload, .. } => payload,
+        }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        self.get_payload().to_vec()
+    }
+    // Queues the message on `writer` as one or more chunks.
+    pub fn write(
+        &self,
+        writer: &mut ChunkWriter,
+        cs_id: u32,
+        timestamp: u32,
+        message_stream_id: u32,
+    ) -> io::Result<()> {
+        writer.write_message(
+            cs_id,
+            timestamp,
+            self.get_message_type_id(),
+            message_stream_id,
+            self.get_payload(),
+        )
+    }
+}
 
-        // set_peer_bandwidth message chunk
-        let basic_header = BasicHeader1::new_from_values(0, 2);
-        let message_header = MessageHeaderType0::new_from_values(0, 5, 6, 0);
-        let set_peer_bandwidth = SetPeerBandwidth::new_from_values(5000000, 2);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        match stream.write_all(&basic_header.data) {
-            Ok(_) => println!("Basic header sent successfully."),
-            Err(e) => println!("Failed to send basic header: {:?}", e),
-        };
-        match stream.write_all(&message_header.data) {
-            Ok(_) => println!("Message header sent successfully."),
-            Err(e) => println!("Failed to send message header: {:?}", e),
-        };
-        match stream.write_all(&set_peer_bandwidth.payload) {
-            Ok(_) => println!("set_peer_bandwidth sent successfully."),
-            Err(e) => println!("Failed to send set_peer_bandwidth: {:?}", e),
-        };
-        match stream.flush() {
-            Ok(_) => println!("flushed successfully."),
-            Err(e) => println!("Failed to flush: {:?}", e),
-        }
-
-        // // stream_begin
-        // let basic_header = BasicHeader1::new_from_values(0, 2);
-        // let message_header = MessageHeaderType0::new_from_values(0, 6, 4, 0);
-        // let mut stream_begin_payload: Cursor<Vec<u8>> = Cursor::new(vec![]);
-        // stream_begin_payload.write_u16::<BigEndian>(0).unwrap();
-        // stream_begin_payload.write_u32::<BigEndian>(1).unwrap();
-        // let stream_begin = UserControlMessage::new(stream_begin_payload.into_inner());
-
-        // match stream.write_all(&basic_header.data) {
-        //     Ok(_) => println!("Basic header sent successfully."),
-        //     Err(e) => println!("Failed to send basic header: {:?}", e),
-        // };
-        // match stream.write_all(&message_header.data) {
-        //     Ok(_) => println!("Message header sent successfully."),
-        //     Err(e) => println!("Failed to send message header: {:?}", e),
-        // };
-        // match stream.write_all(&stream_begin.payload) {
-        //     Ok(_) => println!("set_peer_bandwidth sent successfully."),
-        //     Err(e) => println!("Failed to send set_peer_bandwidth: {:?}", e),
-        // };
-        // match stream.flush() {
-        //     Ok(_) => println!("flushed successfully."),
-        //     Err(e) => println!("Failed to flush: {:?}", e),
-        // }
-        // stream
-        //     .set_read_timeout(Some(Duration::new(5, 0)))
-        //     .expect("Failed to set read timeout");
-
-
-        let mut response_message = Vec::new();
-        Amf0Value::String("_result".to_string())
-            .write_to(&mut response_message).unwrap();
-        Amf0Value::Number(1.0)
-            .write_to(&mut response_message).unwrap();
-        // Amf0Value::Object { class_name, entries }
-        Amf0Value::Object {
-            class_name: None,
-            entries: vec![
-                amf::Pair {
-                    key: "fmsVer".to_string(),
-                    value: Amf0Value::String("FMS/3,5,7,7007".to_string()),
-                },
-                amf::Pair {
-                    key: "capabilities".to_string(),
-                    value: Amf0Value::Number(31.0),
-                },
-            ],
-        }
-        .write_to(&mut response_message).unwrap();
-
-        let basic_header = BasicHeader1::new_from_values(0, 3);
-        let message_header = MessageHeaderType0::new_from_values(0, response_message.len() as u32, 20, 0);
-
-        match stream.write_all(&basic_header.data) {
-            Ok(_) => println!("Basic header sent successfully."),
-            Err(e) => println!("Failed to send basic header: {:?}", e),
-        };
-        match stream.write_all(&message_header.data) {
-            Ok(_) => println!("Message header sent successfully."),
-            Err(e) => println!("Failed to send message header: {:?}", e),
-        };
-        match stream.write_all(&response_message) {
-            Ok(_) => println!("response sent successfully."),
-            Err(e) => println!("Failed to send set_peer_bandwidth: {:?}", e),
-        };
-        match stream.flush() {
-            Ok(_) => println!("flushed successfully."),
-            Err(e) => println!("Failed to flush: {:?}", e),
+    #[test]
+    fn decode_encode_round_trips_every_type() {
+        let payloads: [(u8, &[u8]); 16] = [
+            (SET_CHUNK_SIZE, &[0, 0, 16, 0]),
+            (ABORT, &[0, 0, 0, 6]),
+            (ACKNOWLEDGEMENT, &[0, 1, 0, 0]),
+            (USER_CONTROL, &[0, 6, 0, 0, 0, 42]),
+            (WINDOW_ACKNOWLEDGEMENT_SIZE, &[0, 0x4C, 0x4B, 0x40]),
+            (SET_PEER_BANDWIDTH, &[0, 0x4C, 0x4B, 0x40, 2]),
+            (AUDIO, b"\xaf\x01audio"),
+            (VIDEO, b"\x27\x01\x00\x00\x00video"),
+            (DATA_AMF3, b"\x00amf3 data"),
+            (SHARED_OBJECT_AMF3, b"amf3 shared object"),
+            (COMMAND_AMF3, b"\x00amf3 command"),
+            (DATA_AMF0, b"\x02\x00\x0aonMetaData"),
+            (SHARED_OBJECT_AMF0, b"amf0 shared object"),
+            (
+                COMMAND_AMF0,
+                b"\x02\x00\x0ccreateStream\x00\x40\x00\0\0\0\0\0\0\x05",
+            ),
+            (AGGREGATE, b"aggregate"),
+            (42, b"unknown"),
+        ];
+        for (message_type_id, payload) in payloads {
+            let message = RtmpMessage::decode(message_type_id, payload).unwrap();
+            assert_eq!(message.get_message_type_id(), message_type_id);
+            assert_eq!(message.encode(), payload);
+            assert_eq!(
+                RtmpMessage::decode_bytes(message_type_id, Bytes::copy_from_slice(payload))
+                    .unwrap(),
+                message
+            );
         }
+        assert!(matches!(
+            RtmpMessage::decode(42, b"").unwrap(),
+            RtmpMessage::Unknown {
+                message_type_id: 42,
+                ..
+            }
+        ));
+    }
 
+    #[test]
+    fn user_control_events_round_trip() {
+        let events = [
+            UserControlEvent::StreamBegin(1),
+            UserControlEvent::StreamEof(2),
+            UserControlEvent::StreamDry(3),
+            UserControlEvent::SetBufferLength {
+                stream_id: 4,
+                buffer_length: 3000,
+            },
+            UserControlEvent::StreamIsRecorded(5),
+            UserControlEvent::PingRequest(6),
+            UserControlEvent::PingResponse(7),
+            UserControlEvent::Unknown {
+                event_type: 31,
+                data: vec![1, 2, 3],
+            },
+        ];
+        for event in events {
+            let payload = event.encode();
+            assert_eq!(&payload[..2], u16::to_be_bytes(event.get_event_type()));
+            assert_eq!(UserControlEvent::decode(&payload).unwrap(), event);
+        }
+        assert_eq!(
+            UserControlEvent::SetBufferLength {
+                stream_id: 1,
+                buffer_length: 0x0BB8,
+            }
+            .encode(),
+            [0, 3, 0, 0, 0, 1, 0, 0, 0x0B, 0xB8]
+        );
     }
-}
 
-// enum ProtocolControlMessage {
-//     SetChunkSize, // https://rtmp.veriskope.com/docs/spec/#541set-chunk-size-1
-//     AbortMessage, // https://rtmp.veriskope.com/docs/spec/#542abort-message-2
-//     Acknowledgement, // https://rtmp.veriskope.com/docs/spec/#543acknowledgement-3
-//     WindowAcknowledgementSize, // https://rtmp.veriskope.com/docs/spec/#544window-acknowledgement-size-5
-//     SetPeerBandwidth, // https://rtmp.veriskope.com/docs/spec/#545set-peer-bandwidth-6
-// }
+    #[test]
+    fn rejects_short_user_control_events() {
+        for payload in [&[0][..], &[0, 0, 0, 1], &[0, 3, 0, 0, 0, 1, 0, 0]] {
+            let error = UserControlEvent::decode(payload).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_fixed_payload_length() {
+        for (message_type_id, length) in [
+            (SET_CHUNK_SIZE, 5),
+            (ABORT, 3),
+            (ACKNOWLEDGEMENT, 3),
+            (WINDOW_ACKNOWLEDGEMENT_SIZE, 0),
+            (SET_PEER_BANDWIDTH, 4),
+        ] {
+            let error = RtmpMessage::decode(message_type_id, &vec![0; length]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -1,14 +1,26 @@
 use std::io::Cursor;
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// A timestamp (or delta) field holding this value means the actual value is
+// carried in the 4 byte extended timestamp that follows the message header.
+// https://rtmp.veriskope.com/docs/spec/#5313extended-timestamp
+pub const EXTENDED_TIMESTAMP: u32 = 0xFFFFFF;
+
 pub trait MessageHeader: MessageHeaderClone {
     fn get_timestamp(&self) -> Option<u32>;
     fn get_timestamp_delta(&self) -> Option<u32>;
     fn get_message_length(&self) -> Option<u32>;
     fn get_message_type_id(&self) -> Option<u8>;
     fn get_message_stream_id(&self) -> Option<u32>;
+    fn as_bytes(&self) -> &[u8];
+    // A type 3 header can't tell on its own, it repeats the extended timestamp
+    // whenever the preceding type 0/1/2 header on its chunk stream had one.
+    fn has_extended_timestamp(&self) -> bool {
+        self.get_timestamp().or(self.get_timestamp_delta()) == Some(EXTENDED_TIMESTAMP)
+    }
 }
-trait MessageHeaderClone {
+pub trait MessageHeaderClone {
     fn clone_box(&self) -> Box<dyn MessageHeader>;
 }
 impl<T> MessageHeaderClone for T
@@ -48,16 +60,21 @@ impl MessageHeaderType0 {
         message_stream_id: u32,
     ) -> Self {
         let mut data = Cursor::new([0u8; 11]);
-        data.write_u24::<BigEndian>(timestamp).unwrap(); // timestamp
+        data.write_u24::<BigEndian>(timestamp.min(EXTENDED_TIMESTAMP))
+            .unwrap(); // timestamp
         data.write_u24::<BigEndian>(message_length).unwrap(); // message_length
         data.write_u8(message_type_id).unwrap(); // message_type_id
-        data.write_u32::<BigEndian>(message_stream_id).unwrap(); // message_stream_id
+        // the only little endian field of the protocol
+        data.write_u32::<LittleEndian>(message_stream_id).unwrap(); // message_stream_id
         Self {
             data: data.into_inner(),
         }
     }
 }
 impl MessageHeader for MessageHeaderType0 {
+    fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
     fn get_timestamp(&self) -> Option<u32> {
         let timestamp = Cursor::new(&self.data[..3]).read_u24::<BigEndian>().ok()?;
         Some(timestamp)
@@ -70,7 +87,7 @@ impl MessageHeader for MessageHeaderType0 {
         Some(self.data[6])
     }
     fn get_message_stream_id(&self) -> Option<u32> {
-        let message_stream_id = Cursor::new(&self.data[7..]).read_u32::<BigEndian>().ok()?;
+        let message_stream_id = Cursor::new(&self.data[7..]).read_u32::<LittleEndian>().ok()?;
         Some(message_stream_id)
     }
     fn get_timestamp_delta(&self) -> Option<u32> {
@@ -85,8 +102,21 @@ impl MessageHeaderType1 {
     pub fn new(data: [u8; 7]) -> Self {
         Self { data }
     }
+    pub fn new_from_values(timestamp_delta: u32, message_length: u32, message_type_id: u8) -> Self {
+        let mut data = Cursor::new([0u8; 7]);
+        data.write_u24::<BigEndian>(timestamp_delta.min(EXTENDED_TIMESTAMP))
+            .unwrap(); // timestamp_delta
+        data.write_u24::<BigEndian>(message_length).unwrap(); // message_length
+        data.write_u8(message_type_id).unwrap(); // message_type_id
+        Self {
+            data: data.into_inner(),
+        }
+    }
 }
 impl MessageHeader for MessageHeaderType1 {
+    fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
     fn get_timestamp(&self) -> Option<u32> {
         None
     }
@@ -114,8 +144,19 @@ impl MessageHeaderType2 {
     pub fn new(data: [u8; 3]) -> Self {
         Self { data }
     }
+    pub fn new_from_values(timestamp_delta: u32) -> Self {
+        let mut data = Cursor::new([0u8; 3]);
+        data.write_u24::<BigEndian>(timestamp_delta.min(EXTENDED_TIMESTAMP))
+            .unwrap(); // timestamp_delta
+        Self {
+            data: data.into_inner(),
+        }
+    }
 }
 impl MessageHeader for MessageHeaderType2 {
+    fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
     fn get_timestamp(&self) -> Option<u32> {
         None
     }
@@ -147,6 +188,9 @@ impl MessageHeaderType3 {
     }
 }
 impl MessageHeader for MessageHeaderType3 {
+    fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
     fn get_timestamp(&self) -> Option<u32> {
         None
     }
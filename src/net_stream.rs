@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use crate::subscription::Subscription;
+
+// State of a message stream created with createStream, NetStream commands,
+// media and data of the stream are sent with its id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamState {
+    #[default]
+    Idle,
+    Publishing,
+    Playing,
+}
+
+#[derive(Debug, Clone)]
+pub struct NetStream {
+    state: StreamState,
+    // name given to publish or play
+    name: Option<String>,
+    receive_audio: bool,
+    receive_video: bool,
+    paused: bool,
+    // live stream being played
+    subscription: Option<Arc<Subscription>>,
+    // publisher timestamp sent as 0 to the player
+    timestamp_base: Option<u32>,
+}
+impl Default for NetStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl NetStream {
+    pub fn new() -> Self {
+        Self {
+            state: StreamState::Idle,
+            name: None,
+            receive_audio: true,
+            receive_video: true,
+            paused: false,
+            subscription: None,
+            timestamp_base: None,
+        }
+    }
+    pub fn get_state(&self) -> StreamState {
+        self.state
+    }
+    pub fn get_name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+    pub fn set_publishing(&mut self, name: &str) {
+        self.state = StreamState::Publishing;
+        self.name = Some(name.to_string());
+    }
+    pub fn set_playing(&mut self, name: &str, subscription: Arc<Subscription>) {
+        self.state = StreamState::Playing;
+        self.name = Some(name.to_string());
+        self.subscription = Some(subscription);
+        self.timestamp_base = None;
+    }
+    pub fn set_idle(&mut self) {
+        self.state = StreamState::Idle;
+        self.name = None;
+        self.subscription = None;
+    }
+    pub fn get_subscription(&self) -> Option<&Arc<Subscription>> {
+        self.subscription.as_ref()
+    }
+    // Timestamp for the player, the first frame played starts at 0. Metadata
+    // and sequence headers replayed before it may be much older, they go out
+    // at 0 without moving the base. A publisher reconnecting starts over from
+    // a lower timestamp, so does the player then.
+    pub fn rebase_timestamp(&mut self, timestamp: u32, is_frame: bool) -> u32 {
+        if !is_frame && self.timestamp_base.is_none() {
+            return 0;
+        }
+        let base = self.timestamp_base.get_or_insert(timestamp);
+        if timestamp < *base {
+            *base = timestamp;
+        }
+        timestamp - *base
+    }
+    pub fn get_receive_audio(&self) -> bool {
+        self.receive_audio
+    }
+    pub fn set_receive_audio(&mut self, receive: bool) {
+        self.receive_audio = receive;
+    }
+    pub fn get_receive_video(&self) -> bool {
+        self.receive_video
+    }
+    pub fn set_receive_video(&mut self, receive: bool) {
+        self.receive_video = receive;
+    }
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+}
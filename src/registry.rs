@@ -0,0 +1,215 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    gop_cache::{GopCache, GopCacheConfig},
+    message::{AUDIO, DATA_AMF0, VIDEO, is_audio_sequence_header},
+    subscription::Subscription,
+    video_tag::VideoTag,
+};
+
+// Audio, video or data message of a live stream, cheap to clone so the same
+// payload can be handed to every subscriber.
+#[derive(Debug, Clone)]
+pub struct MediaMessage {
+    pub message_type_id: u8,
+    pub timestamp: u32,
+    pub payload: Bytes,
+}
+impl MediaMessage {
+    // Header of a video message, None for other messages and malformed tags.
+    pub fn get_video_tag(&self) -> Option<VideoTag> {
+        match self.message_type_id {
+            VIDEO => VideoTag::parse(&self.payload).ok(),
+            _ => None,
+        }
+    }
+}
+
+// Encoders send metadata as `@setDataFrame` followed by the handler name and
+// its arguments, players expect it without the `@setDataFrame` prefix.
+const SET_DATA_FRAME: &[u8] = b"\x02\x00\x0d@setDataFrame";
+const ON_METADATA: &[u8] = b"\x02\x00\x0aonMetaData";
+
+// A stream being published, `publisher` is the id of the session sending it.
+#[derive(Debug)]
+pub struct LiveStream {
+    publisher: u64,
+    messages_received: u64,
+    bytes_received: u64,
+    // latest onMetaData and sequence headers, what a player joining in the
+    // middle of the stream needs before it can decode anything
+    metadata: Option<MediaMessage>,
+    audio_sequence_header: Option<MediaMessage>,
+    video_sequence_header: Option<MediaMessage>,
+    // None when disabled for the app
+    gop_cache: Option<GopCache>,
+}
+impl LiveStream {
+    pub fn new(publisher: u64, gop_cache: Option<GopCacheConfig>) -> Self {
+        Self {
+            publisher,
+            messages_received: 0,
+            bytes_received: 0,
+            metadata: None,
+            audio_sequence_header: None,
+            video_sequence_header: None,
+            gop_cache: gop_cache.map(GopCache::new),
+        }
+    }
+    pub fn get_gop_cache(&self) -> Option<&GopCache> {
+        self.gop_cache.as_ref()
+    }
+    pub fn get_metadata(&self) -> Option<&MediaMessage> {
+        self.metadata.as_ref()
+    }
+    pub fn get_audio_sequence_header(&self) -> Option<&MediaMessage> {
+        self.audio_sequence_header.as_ref()
+    }
+    pub fn get_video_sequence_header(&self) -> Option<&MediaMessage> {
+        self.video_sequence_header.as_ref()
+    }
+    pub fn get_publisher(&self) -> u64 {
+        self.publisher
+    }
+    pub fn get_messages_received(&self) -> u64 {
+        self.messages_received
+    }
+    pub fn get_bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+    // Keeps what has to be replayed to new players, returns the message to
+    // forward.
+    fn on_media(&mut self, mut message: MediaMessage) -> MediaMessage {
+        self.messages_received += 1;
+        self.bytes_received += message.payload.len() as u64;
+        if message.message_type_id == DATA_AMF0 && message.payload.starts_with(SET_DATA_FRAME) {
+            message.payload = message.payload.slice(SET_DATA_FRAME.len()..);
+        }
+        match message.message_type_id {
+            DATA_AMF0 if message.payload.starts_with(ON_METADATA) => {
+                self.metadata = Some(message.clone())
+            }
+            AUDIO if is_audio_sequence_header(&message.payload) => {
+                self.audio_sequence_header = Some(message.clone())
+            }
+            VIDEO
+                if message
+                    .get_video_tag()
+                    .is_some_and(|tag| tag.is_sequence_header()) =>
+            {
+                self.video_sequence_header = Some(message.clone())
+            }
+            _ => {
+                if let Some(gop_cache) = &mut self.gop_cache {
+                    gop_cache.push(&message);
+                }
+            }
+        }
+        message
+    }
+    // Cached messages in the order players expect them.
+    fn get_initial_messages(&self) -> impl Iterator<Item = &MediaMessage> {
+        [
+            &self.metadata,
+            &self.video_sequence_header,
+            &self.audio_sequence_header,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.gop_cache.iter().flat_map(GopCache::get_messages))
+    }
+}
+
+// Live streams of the server keyed by `stream_key(app, name)`, shared by all
+// sessions through `SharedRegistry`. Subscribers are kept apart from the
+// streams so a player can wait for a name that isn't published yet, and stays
+// subscribed when the publisher reconnects.
+#[derive(Debug, Default)]
+pub struct StreamRegistry {
+    streams: HashMap<String, LiveStream>,
+    subscribers: HashMap<String, Vec<Arc<Subscription>>>,
+}
+pub type SharedRegistry = Arc<Mutex<StreamRegistry>>;
+
+impl StreamRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn new_shared() -> SharedRegistry {
+        Arc::new(Mutex::new(Self::new()))
+    }
+    pub fn stream_key(app: &str, name: &str) -> String {
+        format!("{}/{}", app, name)
+    }
+    // Registers `key` as published by `publisher`, false if it's already live.
+    pub fn publish(
+        &mut self,
+        key: &str,
+        publisher: u64,
+        gop_cache: Option<GopCacheConfig>,
+    ) -> bool {
+        if self.streams.contains_key(key) {
+            return false;
+        }
+        self.streams
+            .insert(key.to_string(), LiveStream::new(publisher, gop_cache));
+        true
+    }
+    pub fn unpublish(&mut self, key: &str, publisher: u64) {
+        if self.is_published_by(key, publisher) {
+            self.streams.remove(key);
+        }
+    }
+    pub fn is_live(&self, key: &str) -> bool {
+        self.streams.contains_key(key)
+    }
+    pub fn is_published_by(&self, key: &str, publisher: u64) -> bool {
+        self.streams
+            .get(key)
+            .is_some_and(|stream| stream.publisher == publisher)
+    }
+    pub fn get_stream(&self, key: &str) -> Option<&LiveStream> {
+        self.streams.get(key)
+    }
+    // Subscribes to `key`, if it's live the cached metadata, sequence headers
+    // and GOP are queued first.
+    pub fn subscribe(&mut self, key: &str, subscription: Arc<Subscription>) {
+        if let Some(stream) = self.streams.get(key) {
+            for message in stream.get_initial_messages() {
+                subscription.push(message.clone());
+            }
+        }
+        self.subscribers
+            .entry(key.to_string())
+            .or_default()
+            .push(subscription);
+    }
+    pub fn unsubscribe(&mut self, key: &str, subscription: &Arc<Subscription>) {
+        if let Some(subscribers) = self.subscribers.get_mut(key) {
+            subscribers.retain(|s| !Arc::ptr_eq(s, subscription));
+            if subscribers.is_empty() {
+                self.subscribers.remove(key);
+            }
+        }
+    }
+    pub fn get_subscriber_count(&self, key: &str) -> usize {
+        self.subscribers.get(key).map_or(0, Vec::len)
+    }
+    // Media sent by `publisher` on `key`, ignored if it doesn't own the stream.
+    pub fn on_media(&mut self, key: &str, publisher: u64, message: MediaMessage) {
+        match self.streams.get_mut(key) {
+            Some(stream) if stream.publisher == publisher => {
+                let message = stream.on_media(message);
+                for subscription in self.subscribers.get(key).into_iter().flatten() {
+                    subscription.push(message.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    io,
+    net::{Shutdown, SocketAddr, TcpListener, ToSocketAddrs},
+    thread,
+};
+
+use crate::{
+    handshake::HandshakeError,
+    session::{MEDIA_POLL_INTERVAL, Session, SessionConfig},
+};
+
+fn log_session_error(peer: Option<SocketAddr>, error: &io::Error) {
+    let peer = match peer {
+        Some(peer) => peer.to_string(),
+        None => "unknown peer".to_string(),
+    };
+    match HandshakeError::from_io(error) {
+        Some(e) => eprintln!("Rejected handshake from {}: {}", peer, e),
+        None => eprintln!("Session error ({}): {}", peer, error),
+    }
+}
+
+// One OS thread per connection with blocking reads, enough for a handful of
+// peers and keeps embedding trivial.
+pub fn serve_blocking<A: ToSocketAddrs>(addr: A, config: SessionConfig) -> io::Result<()> {
+    config.validate()?;
+    let listener = TcpListener::bind(addr)?;
+    println!("RTMP server listening on {}", listener.local_addr()?);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut s) => {
+                let config = config.clone();
+                thread::spawn(move || {
+                    // wake up regularly even if the peer is silent, for keepalive
+                    // and media of played streams, and give up on a peer that
+                    // doesn't read what it's sent
+                    let timeouts = s
+                        .set_read_timeout(Some(MEDIA_POLL_INTERVAL))
+                        .and_then(|_| s.set_write_timeout(Some(config.subscription.max_behind)));
+                    if let Err(e) = timeouts {
+                        log_session_error(s.peer_addr().ok(), &e);
+                        return;
+                    }
+                    let mut session = Session::with_config(config);
+                    if let Err(e) = session.handle(&mut s) {
+                        log_session_error(s.peer_addr().ok(), &e);
+                        let _ = s.shutdown(Shutdown::Both);
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+// One tokio task per connection, same protocol behavior as `serve_blocking`.
+#[cfg(feature = "tokio")]
+pub async fn serve<A: tokio::net::ToSocketAddrs>(addr: A, config: SessionConfig) -> io::Result<()> {
+    config.validate()?;
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    println!("RTMP server listening on {}", listener.local_addr()?);
+
+    loop {
+        match listener.accept().await {
+            Ok((mut s, peer)) => {
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let mut session = Session::with_config(config);
+                    if let Err(e) = session.handle_async(&mut s).await {
+                        log_session_error(Some(peer), &e);
+                        let _ = tokio::io::AsyncWriteExt::shutdown(&mut s).await;
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Error: {}", e);
+            }
+        }
+    }
+}
@@ -1,124 +1,838 @@
 use crate::{
-    basic_header::BasicHeader,
-    chunk::Chunk,
+    chunk_decoder::{ChunkDecoder, RawMessage},
+    chunk_writer::ChunkWriter,
+    command::{self, Command, ConnectParams},
+    flow_control::FlowControl,
+    gop_cache::GopCacheConfig,
     handshake::Handshake,
-    message::{CommandMessageAmf0, Message, SetChunkSize},
+    message::{
+        AUDIO, AUDIO_CS_ID, Acknowledgement, DATA_CS_ID, Message, PROTOCOL_CONTROL_CS_ID,
+        RtmpMessage, SetChunkSize, UserControlEvent, UserControlMessage, VIDEO, VIDEO_CS_ID,
+        is_audio_sequence_header,
+    },
+    net_stream::{NetStream, StreamState},
+    registry::{MediaMessage, SharedRegistry, StreamRegistry},
+    subscription::{SubscriptionConfig, Waker},
+    video_tag::VideoTag,
+};
+use bytes::Bytes;
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
 };
-use core::fmt;
-use std::{collections::HashMap, io, net::TcpStream};
 
-pub struct PrecedingChunkAttributes {
-    pub message_type_id: u8,
-}
-pub(crate) struct Session<'a> {
-    preceding: HashMap<u32, PrecedingChunkAttributes>,
-    // bytes_received: u32,
-    stream: &'a mut TcpStream,
+// How often drivers call `Session::poll` when no data comes in.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Blocking drivers can't be woken up when media for a player comes in, they
+// poll this often instead.
+pub const MEDIA_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    // outbound chunk size, announced with Set Chunk Size right after connect
+    pub chunk_size: u32,
+    // PingRequest period once the handshake is done, None disables keepalive
+    pub ping_interval: Option<Duration>,
+    // the peer is dropped after this many ping intervals without a PingResponse
+    pub ping_timeout_intervals: u32,
+    // live streams, clones of the config share the same registry
+    pub registry: SharedRegistry,
+    // play of a name that isn't live waits for a publisher instead of failing
+    // with NetStream.Play.StreamNotFound
+    pub wait_for_publisher: bool,
+    // GOP cache of published streams, None disables it
+    pub gop_cache: Option<GopCacheConfig>,
+    // `gop_cache` overrides by app, e.g. None for low latency apps
+    pub app_gop_cache: HashMap<String, Option<GopCacheConfig>>,
+    // outbound queue of each played stream
+    pub subscription: SubscriptionConfig,
 }
-impl<'a> Session<'a> {
-    pub fn new(stream: &'a mut TcpStream) -> Self {
+impl Default for SessionConfig {
+    fn default() -> Self {
         Self {
-            preceding: HashMap::new(),
-            // bytes_received: 0,
-            stream,
+            chunk_size: 4096,
+            ping_interval: Some(Duration::from_secs(30)),
+            ping_timeout_intervals: 3,
+            registry: StreamRegistry::new_shared(),
+            wait_for_publisher: false,
+            gop_cache: Some(GopCacheConfig::default()),
+            app_gop_cache: HashMap::new(),
+            subscription: SubscriptionConfig::default(),
+        }
+    }
+}
+impl SessionConfig {
+    // Checked by the servers before accepting connections, the chunk size
+    // follows the same rules as one received from a peer.
+    pub fn validate(&self) -> io::Result<()> {
+        self.get_chunk_size()?;
+        if let Some(interval) = self.ping_interval {
+            let timeout = interval.checked_mul(self.ping_timeout_intervals);
+            if interval.is_zero() || timeout.is_none_or(|timeout| timeout.is_zero()) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid keepalive, ping every {:?} for {} intervals",
+                        interval, self.ping_timeout_intervals
+                    ),
+                ));
+            }
         }
+        Ok(())
     }
-    // fn get_bytes_received(&self) -> u32 {
-    //     self.bytes_received
-    // }
-    pub fn handle(&mut self) -> io::Result<()> {
-        Handshake::new().response(self.stream)?;
+    // The outbound chunk size to announce and apply.
+    pub fn get_chunk_size(&self) -> io::Result<u32> {
+        SetChunkSize::new(self.chunk_size.to_be_bytes()).validate()
+    }
+    pub fn get_gop_cache(&self, app: &str) -> Option<GopCacheConfig> {
+        match self.app_gop_cache.get(app) {
+            Some(gop_cache) => *gop_cache,
+            None => self.gop_cache,
+        }
+    }
+}
 
-        loop {
-            let chunk = Chunk::read_from_stream(self.stream)?;
-            let fmt = chunk.basic_header.get_fmt();
-            let cs_id = chunk.basic_header.get_cs_id();
-            let message_type_id = match fmt {
-                0 | 1 => chunk.message_header.get_message_type_id().unwrap(),
-                _ => self.preceding.get(&cs_id).unwrap().message_type_id,
-            };
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
 
-            if let Some(message_stream_id) = chunk.message_header.get_message_stream_id() {
-                println!("message_stream_id: {}", message_stream_id,);
+// Protocol state of one connection. It doesn't own a socket: bytes read from
+// the peer go in through `push` and whatever has to be sent back is collected
+// with `take_output`, `handle` drives it over a blocking stream.
+pub struct Session {
+    // unique in the process, identifies the session in the registry
+    id: u64,
+    config: SessionConfig,
+    handshake: Handshake,
+    decoder: ChunkDecoder,
+    writer: ChunkWriter,
+    output: Vec<u8>,
+    // every byte received from the peer, handshake included, wraps around
+    bytes_received: u32,
+    // bytes_received when the last Acknowledgement was sent
+    bytes_acknowledged: u32,
+    // the peer's Window Acknowledgement Size, None until it sends one
+    window_acknowledgement_size: Option<u32>,
+    flow_control: FlowControl,
+    // SetBufferLength from the player, milliseconds by message stream id, the
+    // player of a stream can lag behind that much more before it's dropped
+    buffer_lengths: HashMap<u32, u32>,
+    // ping timestamps are milliseconds since this instant
+    created: Instant,
+    // timestamp and send time of the PingRequest waiting for an answer
+    ping_sent: Option<(u32, Instant)>,
+    // last PingResponse, or the end of the handshake before the first one
+    last_pong: Option<Instant>,
+    rtt: Option<Duration>,
+    // set once the NetConnection is connected
+    connect_params: Option<ConnectParams>,
+    // streams created with createStream, by message stream id
+    streams: HashMap<u32, NetStream>,
+    // handed to subscriptions, see `set_waker`
+    waker: Option<Waker>,
+}
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for Session {
+    // a publisher going away takes its streams offline, a player unsubscribes
+    fn drop(&mut self) {
+        for stream in self.streams.values() {
+            self.unpublish(stream);
+        }
+    }
+}
+impl Session {
+    pub fn new() -> Self {
+        Self::with_config(SessionConfig::default())
+    }
+    pub fn with_config(config: SessionConfig) -> Self {
+        Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            config,
+            handshake: Handshake::new(),
+            decoder: ChunkDecoder::new(),
+            writer: ChunkWriter::new(),
+            output: vec![],
+            bytes_received: 0,
+            bytes_acknowledged: 0,
+            window_acknowledgement_size: None,
+            flow_control: FlowControl::new(),
+            buffer_lengths: HashMap::new(),
+            created: Instant::now(),
+            ping_sent: None,
+            last_pong: None,
+            rtt: None,
+            connect_params: None,
+            streams: HashMap::new(),
+            waker: None,
+        }
+    }
+    pub fn get_bytes_received(&self) -> u32 {
+        self.bytes_received
+    }
+    pub fn set_window_acknowledgement_size(&mut self, window_size: u32) {
+        self.window_acknowledgement_size = Some(window_size);
+    }
+    pub fn get_config(&self) -> &SessionConfig {
+        &self.config
+    }
+    pub fn get_writer(&mut self) -> &mut ChunkWriter {
+        &mut self.writer
+    }
+    pub fn set_connect_params(&mut self, params: ConnectParams) {
+        self.connect_params = Some(params);
+    }
+    pub fn get_connect_params(&self) -> Option<&ConnectParams> {
+        self.connect_params.as_ref()
+    }
+    // Allocates the lowest free message stream id, 0 is the NetConnection.
+    pub fn create_stream(&mut self) -> u32 {
+        let stream_id = (1..)
+            .find(|stream_id| !self.streams.contains_key(stream_id))
+            .unwrap();
+        self.streams.insert(stream_id, NetStream::new());
+        stream_id
+    }
+    pub fn delete_stream(&mut self, stream_id: u32) -> Option<NetStream> {
+        let stream = self.streams.remove(&stream_id)?;
+        self.unpublish(&stream);
+        self.buffer_lengths.remove(&stream_id);
+        self.flow_control.remove_stream(stream_id);
+        Some(stream)
+    }
+    // Stops publishing or playing on the stream, it stays allocated.
+    pub fn stop_stream(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.get(&stream_id) {
+            self.unpublish(stream);
+        }
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.set_idle();
+        }
+    }
+    pub fn get_id(&self) -> u64 {
+        self.id
+    }
+    // Registry key of stream `name` in the connected app.
+    pub fn get_stream_key(&self, name: &str) -> io::Result<String> {
+        match &self.connect_params {
+            Some(params) => Ok(StreamRegistry::stream_key(&params.app, name)),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "NetStream command before connect",
+            )),
+        }
+    }
+    // Takes the stream out of the registry, as publisher or player.
+    fn unpublish(&self, stream: &NetStream) {
+        let Some(key) = stream
+            .get_name()
+            .and_then(|name| self.get_stream_key(name).ok())
+        else {
+            return;
+        };
+        let mut registry = self.config.registry.lock().unwrap();
+        match (stream.get_state(), stream.get_subscription()) {
+            (StreamState::Publishing, _) => {
+                println!("Unpublished {}", key);
+                registry.unpublish(&key, self.id);
+            }
+            (StreamState::Playing, Some(subscription)) => {
+                println!("Stopped playing {}", key);
+                registry.unsubscribe(&key, subscription);
+            }
+            _ => {}
+        }
+    }
+    // Called from subscriptions of this session when they get new media, async
+    // drivers use it to wake up without waiting for the peer.
+    pub fn set_waker(&mut self, waker: Option<Waker>) {
+        self.waker = waker;
+    }
+    pub fn get_waker(&self) -> Option<Waker> {
+        self.waker.clone()
+    }
+    // Sends what the played live streams got since the last call.
+    fn forward_media(&mut self, now: Instant) -> io::Result<()> {
+        let stream_ids: Vec<u32> = self.streams.keys().copied().collect();
+        for stream_id in stream_ids {
+            let Some(subscription) = self.streams[&stream_id].get_subscription().cloned() else {
+                continue;
             };
-
-            println!("fmt: {}", fmt);
-            println!("cs_id: {}", cs_id);
-
-            println!("message_type_id: {}", message_type_id,);
-            match message_type_id {
-                1 => {
-                    println!("Handle Set Chunk Size (1)");
-                    let message = SetChunkSize::new(chunk.chunk_data.try_into().unwrap());
-                    message.handle(self.stream);
-                }
-                2 => {
-                    println!("Abort Message (2)")
-                }
-                3 => {
-                    println!("Acknowledgement (3)")
-                }
-                4 => {
-                    println!("User Control Messages (4)")
-                }
-                5 => {
-                    println!("Window Acknowledgement Size (5)")
-                }
-                6 => {
-                    println!("Set Peer Bandwidth (6)")
-                }
-                // 7 =>
-                8 => {
-                    println!("Audio Message (8)")
-                }
-                9 => {
-                    println!("Video Message (9)")
-                }
-                // 10 =>
-                // 11 =>
-                // 12 =>
-                // 13 =>
-                // 14 =>
-                15 => {
-                    println!("Data Message Amf3 (15)")
-                }
-                16 => {
-                    println!("Shared Object Message (16)")
-                }
-                17 => {
-                    println!("Command Message Amf3 (17)")
-                }
-                18 => {
-                    println!("Data Message Amf0 (18)")
-                }
-                19 => {
-                    println!("Shared Object Message (19)")
+            if subscription.is_behind(now) {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "player of stream {} fell behind, {} video and {} audio frames dropped",
+                        stream_id,
+                        subscription.get_dropped_video(),
+                        subscription.get_dropped_audio()
+                    ),
+                ));
+            }
+            for message in subscription.take() {
+                let stream = self.streams.get_mut(&stream_id).unwrap();
+                let skip = match message.message_type_id {
+                    AUDIO => !stream.get_receive_audio(),
+                    VIDEO => !stream.get_receive_video(),
+                    _ => false,
+                };
+                if skip || stream.is_paused() {
+                    continue;
                 }
-                20 => {
-                    println!("Command Message Amf0 (20)");
-                    let message = CommandMessageAmf0::new(chunk.chunk_data.try_into().unwrap());
-                    message.handle(self.stream);
+                let is_frame = match message.message_type_id {
+                    AUDIO => !is_audio_sequence_header(&message.payload),
+                    VIDEO => !message
+                        .get_video_tag()
+                        .is_some_and(|tag| tag.is_sequence_header()),
+                    _ => false,
+                };
+                let timestamp = stream.rebase_timestamp(message.timestamp, is_frame);
+                let message = RtmpMessage::decode_bytes(message.message_type_id, message.payload)?;
+                self.send_media(stream_id, timestamp, &message)?;
+            }
+        }
+        Ok(())
+    }
+    // Audio, video and data received on a publishing stream go to the registry.
+    fn on_media(&mut self, message_stream_id: u32, message: MediaMessage) -> io::Result<()> {
+        let Some(stream) = self.streams.get(&message_stream_id) else {
+            return Ok(());
+        };
+        if let (StreamState::Publishing, Some(name)) = (stream.get_state(), stream.get_name()) {
+            let key = self.get_stream_key(name)?;
+            self.config
+                .registry
+                .lock()
+                .unwrap()
+                .on_media(&key, self.id, message);
+        }
+        Ok(())
+    }
+    pub fn get_stream(&self, stream_id: u32) -> Option<&NetStream> {
+        self.streams.get(&stream_id)
+    }
+    pub fn get_stream_mut(&mut self, stream_id: u32) -> Option<&mut NetStream> {
+        self.streams.get_mut(&stream_id)
+    }
+    pub fn get_flow_control(&mut self) -> &mut FlowControl {
+        &mut self.flow_control
+    }
+    pub fn set_buffer_length(&mut self, message_stream_id: u32, buffer_length: u32) {
+        self.buffer_lengths.insert(message_stream_id, buffer_length);
+        let subscription = self
+            .streams
+            .get(&message_stream_id)
+            .and_then(NetStream::get_subscription);
+        if let Some(subscription) = subscription {
+            subscription.set_buffer_length(Duration::from_millis(buffer_length as u64));
+        }
+    }
+    pub fn get_buffer_length(&self, message_stream_id: u32) -> Option<u32> {
+        self.buffer_lengths.get(&message_stream_id).copied()
+    }
+    // Round trip time measured with the last answered ping.
+    pub fn get_rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+    pub fn on_ping_response(&mut self, timestamp: u32) {
+        let now = Instant::now();
+        match self.ping_sent {
+            Some((sent, sent_at)) if sent == timestamp => {
+                self.rtt = Some(now - sent_at);
+                self.ping_sent = None;
+                self.last_pong = Some(now);
+            }
+            _ => println!("Unexpected PingResponse {}", timestamp),
+        }
+    }
+    // Work that doesn't come from the peer: forwards media of played streams,
+    // sends a PingRequest every `ping_interval` and fails with `TimedOut` once
+    // the peer went `ping_timeout_intervals` without answering or a player fell
+    // behind for too long. Drivers call it at least every `POLL_INTERVAL`, and
+    // when the waker fires.
+    pub fn poll(&mut self, now: Instant) -> io::Result<()> {
+        self.forward_media(now)?;
+        self.keepalive(now)
+    }
+    fn keepalive(&mut self, now: Instant) -> io::Result<()> {
+        let Some(interval) = self.config.ping_interval else {
+            return Ok(());
+        };
+        if !self.handshake.is_done() {
+            return Ok(());
+        }
+        let last_pong = *self.last_pong.get_or_insert(now);
+        let timeout = interval.saturating_mul(self.config.ping_timeout_intervals);
+        if now.saturating_duration_since(last_pong) >= timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "no PingResponse for {} ping intervals",
+                    self.config.ping_timeout_intervals
+                ),
+            ));
+        }
+        let due = match self.ping_sent {
+            Some((_, sent_at)) => now.saturating_duration_since(sent_at) >= interval,
+            None => now.saturating_duration_since(last_pong) >= interval,
+        };
+        if due {
+            let timestamp = now.saturating_duration_since(self.created).as_millis() as u32;
+            self.send_user_control(&UserControlEvent::PingRequest(timestamp))?;
+            self.ping_sent = Some((timestamp, now));
+        }
+        Ok(())
+    }
+    pub fn send_user_control(&mut self, event: &UserControlEvent) -> io::Result<()> {
+        RtmpMessage::UserControl(UserControlMessage::new_from_event(event)).write(
+            &mut self.writer,
+            PROTOCOL_CONTROL_CS_ID,
+            0,
+            0,
+        )
+    }
+    // Queues an audio, video or data message for the peer unless flow control
+    // drops it, returns whether it was queued.
+    pub fn send_media(
+        &mut self,
+        message_stream_id: u32,
+        timestamp: u32,
+        message: &RtmpMessage,
+    ) -> io::Result<bool> {
+        if !self.flow_control.admit(message_stream_id, message) {
+            return Ok(false);
+        }
+        let cs_id = match message {
+            RtmpMessage::Audio(_) => AUDIO_CS_ID,
+            RtmpMessage::Video(_) => VIDEO_CS_ID,
+            _ => DATA_CS_ID,
+        };
+        message.write(&mut self.writer, cs_id, timestamp, message_stream_id)?;
+        Ok(true)
+    }
+    // Set a read timeout of `MEDIA_POLL_INTERVAL` on the stream for keepalive
+    // and playing to work, timed out reads only trigger `poll`.
+    pub fn handle<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        let mut buffer = [0u8; 4096];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => {
+                    println!("Connection closed by peer");
+                    return Ok(());
                 }
-                22 => {
-                    println!("Aggregate Message (22)")
+                Ok(n) => self.push(&buffer[..n])?,
+                Err(e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            self.poll(Instant::now())?;
+            let output = self.take_output();
+            if !output.is_empty() {
+                stream.write_all(&output)?;
+                stream.flush()?;
+            }
+        }
+    }
+    #[cfg(feature = "tokio")]
+    pub async fn handle_async<S>(&mut self, stream: &mut S) -> io::Result<()>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut buffer = [0u8; 4096];
+        let mut ticks = tokio::time::interval(POLL_INTERVAL);
+        let notify = std::sync::Arc::new(tokio::sync::Notify::new());
+        let waker = notify.clone();
+        self.set_waker(Some(std::sync::Arc::new(move || waker.notify_one())));
+        loop {
+            tokio::select! {
+                n = stream.read(&mut buffer) => {
+                    let n = n?;
+                    if n == 0 {
+                        println!("Connection closed by peer");
+                        return Ok(());
+                    }
+                    self.push(&buffer[..n])?;
                 }
-                _ => {
-                    println!("Unhandled message!")
+                _ = ticks.tick() => {}
+                _ = notify.notified() => {}
+            }
+            self.poll(Instant::now())?;
+            let output = self.take_output();
+            if !output.is_empty() {
+                // a peer that doesn't read is as far behind as it gets
+                let max_behind = self.config.subscription.max_behind;
+                tokio::time::timeout(max_behind, stream.write_all(&output))
+                    .await
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "write timed out"))??;
+                stream.flush().await?;
+            }
+        }
+    }
+    pub fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        self.bytes_received = self.bytes_received.wrapping_add(data.len() as u32);
+        let mut data = data;
+        if !self.handshake.is_done() {
+            let consumed = self.handshake.push(data)?;
+            self.output.extend(self.handshake.take_output());
+            data = &data[consumed..];
+        }
+        if !self.handshake.is_done() {
+            return Ok(());
+        }
+
+        self.decoder.push(data);
+        while let Some(message) = self.decoder.next_message()? {
+            self.dispatch(message)?;
+        }
+        self.acknowledge()?;
+        Ok(())
+    }
+    // Bytes waiting to be sent to the peer.
+    pub fn take_output(&mut self) -> Bytes {
+        let mut output = std::mem::take(&mut self.output);
+        output.extend(self.writer.take_output());
+        self.flow_control.on_sent(output.len());
+        Bytes::from(output)
+    }
+    // Sends an Acknowledgement each time another window worth of bytes came in.
+    // https://rtmp.veriskope.com/docs/spec/#543acknowledgement-3
+    fn acknowledge(&mut self) -> io::Result<()> {
+        let Some(window_size) = self.window_acknowledgement_size else {
+            return Ok(());
+        };
+        if self.bytes_received.wrapping_sub(self.bytes_acknowledged) >= window_size {
+            RtmpMessage::Acknowledgement(Acknowledgement::new_from_values(self.bytes_received))
+                .write(&mut self.writer, PROTOCOL_CONTROL_CS_ID, 0, 0)?;
+            self.bytes_acknowledged = self.bytes_received;
+        }
+        Ok(())
+    }
+    fn dispatch(&mut self, message: RawMessage) -> io::Result<()> {
+        let RawMessage {
+            cs_id,
+            header,
+            payload,
+        } = message;
+        println!("cs_id: {}", cs_id);
+        println!("message_stream_id: {}", header.message_stream_id);
+        println!("timestamp: {}", header.timestamp);
+        println!("message_type_id: {}", header.message_type_id);
+        match RtmpMessage::decode_bytes(header.message_type_id, payload)? {
+            RtmpMessage::SetChunkSize(message) => {
+                println!("Handle Set Chunk Size (1)");
+                message.handle(self)?;
+            }
+            RtmpMessage::Abort(message) => {
+                println!("Abort Message (2), cs_id: {}", message.get_cs_id());
+            }
+            RtmpMessage::Acknowledgement(message) => {
+                println!("Acknowledgement (3): {}", message.get_sequence_number());
+                message.handle(self)?;
+            }
+            RtmpMessage::UserControl(message) => {
+                println!("User Control Messages (4)");
+                message.handle(self)?;
+            }
+            RtmpMessage::WindowAcknowledgementSize(message) => {
+                println!(
+                    "Window Acknowledgement Size (5): {}",
+                    message.get_window_size()
+                );
+                message.handle(self)?;
+            }
+            RtmpMessage::SetPeerBandwidth(message) => {
+                println!(
+                    "Set Peer Bandwidth (6): {}, limit type {}",
+                    message.get_window_size(),
+                    message.get_limit_type()
+                );
+                message.handle(self)?;
+            }
+            RtmpMessage::Audio(payload) | RtmpMessage::Video(payload) => {
+                let tag = match header.message_type_id {
+                    VIDEO => VideoTag::parse(&payload).ok(),
+                    _ => None,
+                };
+                match tag {
+                    Some(tag) => println!(
+                        "Video Message (9): {:?} {:?} {:?}, pts {}",
+                        tag.get_codec(),
+                        tag.get_frame_type(),
+                        tag.get_packet_type(),
+                        tag.get_pts(header.timestamp)
+                    ),
+                    None => println!("Media Message ({})", header.message_type_id),
                 }
+                self.on_media(
+                    header.message_stream_id,
+                    MediaMessage {
+                        message_type_id: header.message_type_id,
+                        timestamp: header.timestamp,
+                        payload,
+                    },
+                )?;
+            }
+            RtmpMessage::DataAmf3(_) => {
+                println!("Data Message Amf3 (15)")
             }
+            RtmpMessage::SharedObjectAmf3(_) => {
+                println!("Shared Object Message (16)")
+            }
+            RtmpMessage::CommandAmf3(payload) => {
+                // sent once connect negotiated objectEncoding 3, AMF0 answers
+                // are still understood
+                println!("Command Message Amf3 (17)");
+                command::dispatch(
+                    self,
+                    header.message_stream_id,
+                    &Command::decode_amf3(&payload)?,
+                )?;
+            }
+            RtmpMessage::DataAmf0(payload) => {
+                println!("Data Message Amf0 (18)");
+                self.on_media(
+                    header.message_stream_id,
+                    MediaMessage {
+                        message_type_id: header.message_type_id,
+                        timestamp: header.timestamp,
+                        payload,
+                    },
+                )?;
+            }
+            RtmpMessage::SharedObjectAmf0(_) => {
+                println!("Shared Object Message (19)")
+            }
+            RtmpMessage::CommandAmf0(message) => {
+                println!("Command Message Amf0 (20)");
+                command::dispatch(self, header.message_stream_id, &message.get_command()?)?;
+            }
+            RtmpMessage::Aggregate(_) => {
+                println!("Aggregate Message (22)")
+            }
+            RtmpMessage::Unknown { .. } => {
+                println!("Unhandled message!")
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        handshake::HANDSHAKE_SIZE,
+        message::{AcknowledgementWindowSize, USER_CONTROL},
+    };
 
-            if let Some(timestamp) = chunk.message_header.get_timestamp() {
-                println!("timestamp: {}", timestamp);
+    // A session past the handshake and the client end of its chunk stream.
+    struct Peer {
+        session: Session,
+        writer: ChunkWriter,
+        decoder: ChunkDecoder,
+    }
+    impl Peer {
+        fn new(config: SessionConfig) -> Self {
+            let mut session = Session::with_config(config);
+            let mut client = Handshake::new_client(false);
+            session.push(&client.take_output()).unwrap();
+            client.push(&session.take_output()).unwrap();
+            session.push(&client.take_output()).unwrap();
+            assert!(client.is_done());
+            Self {
+                session,
+                writer: ChunkWriter::new(),
+                decoder: ChunkDecoder::new(),
             }
-            if let Some(get_timestamp_delta) = chunk.message_header.get_timestamp_delta() {
-                println!("timestamp_delta: {}", get_timestamp_delta);
+        }
+        // Returns the number of bytes pushed.
+        fn send(&mut self, message: &RtmpMessage) -> u32 {
+            message
+                .write(&mut self.writer, PROTOCOL_CONTROL_CS_ID, 0, 0)
+                .unwrap();
+            let data = self.writer.take_output();
+            self.session.push(&data).unwrap();
+            data.len() as u32
+        }
+        fn receive(&mut self) -> Vec<RtmpMessage> {
+            self.decoder
+                .decode(self.session.take_output())
+                .unwrap()
+                .into_iter()
+                .map(|message| {
+                    RtmpMessage::decode_bytes(message.header.message_type_id, message.payload)
+                        .unwrap()
+                })
+                .collect()
+        }
+    }
+    fn user_control(event: &UserControlEvent) -> RtmpMessage {
+        RtmpMessage::UserControl(UserControlMessage::new_from_event(event))
+    }
+
+    #[test]
+    fn answers_ping_request() {
+        let mut peer = Peer::new(SessionConfig::default());
+        peer.send(&user_control(&UserControlEvent::PingRequest(1234)));
+        assert_eq!(
+            peer.receive(),
+            [user_control(&UserControlEvent::PingResponse(1234))]
+        );
+        assert_eq!(
+            RtmpMessage::decode(USER_CONTROL, &[0, 7, 0, 0, 4, 0xD2]).unwrap(),
+            user_control(&UserControlEvent::PingResponse(1234))
+        );
+    }
+
+    // Sends Acknowledgements until `count` came back, returns their sequence
+    // numbers and the bytes pushed when each was sent.
+    fn acknowledgements(peer: &mut Peer, received: u32, count: usize) -> Vec<(u32, u32)> {
+        let mut received = received;
+        let mut acknowledgements = vec![];
+        while acknowledgements.len() < count {
+            received = received.wrapping_add(peer.send(&RtmpMessage::Acknowledgement(
+                Acknowledgement::new_from_values(0),
+            )));
+            for message in peer.receive() {
+                let RtmpMessage::Acknowledgement(acknowledgement) = message else {
+                    panic!("unexpected {:?}", message);
+                };
+                acknowledgements.push((acknowledgement.get_sequence_number(), received));
             }
+        }
+        acknowledgements
+    }
 
-            self.preceding
-                .entry(cs_id)
-                .and_modify(|attributes| attributes.message_type_id = message_type_id)
-                .or_insert(PrecedingChunkAttributes { message_type_id: message_type_id });
+    #[test]
+    fn acknowledges_every_window() {
+        let mut peer = Peer::new(SessionConfig::default());
+        // C0, C1 and C2 count too
+        let mut received = 1 + 2 * HANDSHAKE_SIZE as u32;
+        received += peer.send(&RtmpMessage::WindowAcknowledgementSize(
+            AcknowledgementWindowSize::new_from_values(5000),
+        ));
+        let acknowledgements = acknowledgements(&mut peer, received, 2);
+        for (sequence_number, received) in &acknowledgements {
+            assert_eq!(sequence_number, received);
         }
-        Ok(())
+        assert!(acknowledgements[0].0 >= 5000 && acknowledgements[0].0 < 5000 + 16);
+        assert!(acknowledgements[1].0 - acknowledgements[0].0 >= 5000);
+    }
+
+    #[test]
+    fn acknowledges_across_wrap() {
+        let mut peer = Peer::new(SessionConfig::default());
+        peer.send(&RtmpMessage::WindowAcknowledgementSize(
+            AcknowledgementWindowSize::new_from_values(100),
+        ));
+        // the handshake alone is past that window
+        assert_eq!(peer.receive().len(), 1);
+        let received = u32::MAX - 50;
+        peer.session.bytes_received = received;
+        peer.session.bytes_acknowledged = received;
+        let [(sequence_number, pushed)] = acknowledgements(&mut peer, received, 1)[..] else {
+            unreachable!();
+        };
+        assert_eq!(sequence_number, pushed);
+        assert!(sequence_number < 100);
+    }
+
+    #[test]
+    fn sends_ping_every_interval() {
+        let mut peer = Peer::new(SessionConfig {
+            ping_interval: Some(Duration::from_secs(1)),
+            ..SessionConfig::default()
+        });
+        let start = Instant::now();
+        peer.session.poll(start).unwrap();
+        peer.session
+            .poll(start + Duration::from_millis(999))
+            .unwrap();
+        assert!(peer.receive().is_empty());
+        peer.session.poll(start + Duration::from_secs(1)).unwrap();
+        let [RtmpMessage::UserControl(ping)] = &peer.receive()[..] else {
+            panic!("no ping sent");
+        };
+        assert!(matches!(
+            ping.get_event().unwrap(),
+            UserControlEvent::PingRequest(_)
+        ));
+    }
+
+    #[test]
+    fn records_rtt() {
+        let interval = Duration::from_millis(20);
+        let mut peer = Peer::new(SessionConfig {
+            ping_interval: Some(interval),
+            ..SessionConfig::default()
+        });
+        peer.session.poll(Instant::now()).unwrap();
+        std::thread::sleep(interval);
+        let sent_at = Instant::now();
+        peer.session.poll(sent_at).unwrap();
+        let [RtmpMessage::UserControl(ping)] = &peer.receive()[..] else {
+            panic!("no ping sent");
+        };
+        let UserControlEvent::PingRequest(timestamp) = ping.get_event().unwrap() else {
+            panic!("not a PingRequest");
+        };
+        assert_eq!(peer.session.get_rtt(), None);
+        std::thread::sleep(Duration::from_millis(10));
+        peer.send(&user_control(&UserControlEvent::PingResponse(timestamp)));
+        let rtt = peer.session.get_rtt().unwrap();
+        assert!(rtt >= Duration::from_millis(10) && rtt <= sent_at.elapsed());
+    }
+
+    #[test]
+    fn times_out_without_ping_response() {
+        let mut peer = Peer::new(SessionConfig {
+            ping_interval: Some(Duration::from_secs(1)),
+            ping_timeout_intervals: 3,
+            ..SessionConfig::default()
+        });
+        let start = Instant::now();
+        for seconds in 0..3 {
+            peer.session
+                .poll(start + Duration::from_secs(seconds))
+                .unwrap();
+        }
+        assert_eq!(peer.receive().len(), 2);
+        let error = peer
+            .session
+            .poll(start + Duration::from_secs(3))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn validates_keepalive() {
+        let config = |ping_interval, ping_timeout_intervals| SessionConfig {
+            ping_interval,
+            ping_timeout_intervals,
+            ..SessionConfig::default()
+        };
+        assert!(config(None, 0).validate().is_ok());
+        assert!(config(Some(Duration::from_secs(30)), 3).validate().is_ok());
+        assert!(config(Some(Duration::ZERO), 3).validate().is_err());
+        assert!(config(Some(Duration::from_secs(30)), 0).validate().is_err());
+        assert!(config(Some(Duration::MAX), 2).validate().is_err());
+    }
+
+    #[test]
+    fn validates_chunk_size() {
+        let config = |chunk_size| SessionConfig {
+            chunk_size,
+            ..SessionConfig::default()
+        };
+        assert!(config(0).validate().is_err());
+        assert!(config(0x80000000).validate().is_err());
+        assert_eq!(config(4096).get_chunk_size().unwrap(), 4096);
+        assert_eq!(config(0x7FFFFFFF).get_chunk_size().unwrap(), 0xFFFFFF);
     }
 }
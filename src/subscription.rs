@@ -0,0 +1,331 @@
+use std::{
+    collections::VecDeque,
+    fmt,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    message::{AUDIO, VIDEO, is_audio_sequence_header},
+    registry::MediaMessage,
+};
+
+// Called when a subscription gets new messages, lets a driver waiting on
+// the socket wake up and send them.
+pub type Waker = Arc<dyn Fn() + Send + Sync>;
+
+// Bounds of a player's queue. When it's full non-keyframe video is dropped
+// first, then audio, then the oldest keyframes and data, and a player still
+// dropping after `max_behind`, plus the buffer length it asked for, is
+// disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SubscriptionConfig {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub max_behind: Duration,
+}
+impl Default for SubscriptionConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 2048,
+            max_bytes: 16 * 1024 * 1024,
+            max_behind: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: VecDeque<MediaMessage>,
+    bytes: usize,
+    // video frames were dropped, the following ones can't be decoded until the
+    // next keyframe
+    waiting_for_keyframe: bool,
+    dropped_video: u64,
+    dropped_audio: u64,
+    // something was dropped since the last `take`
+    dropping: bool,
+    // first drop of the current run of takes that all had drops
+    behind_since: Option<Instant>,
+    // full of sequence headers only, nothing left to drop
+    overflowed: bool,
+    // SetBufferLength of the player, it can lag behind that much more
+    buffer_length: Duration,
+}
+impl Queue {
+    fn is_full(&self, config: &SubscriptionConfig) -> bool {
+        self.messages.len() > config.max_messages || self.bytes > config.max_bytes
+    }
+    fn get_last_keyframe(&self) -> Option<usize> {
+        self.messages.iter().rposition(is_keyframe)
+    }
+    // Removes the messages in `range` matching `drop`, returns how many.
+    fn drop_where(&mut self, range: Range<usize>, drop: impl Fn(&MediaMessage) -> bool) -> u64 {
+        let before = self.messages.len();
+        let mut bytes = self.bytes;
+        let mut index = 0;
+        self.messages.retain(|message| {
+            let keep = !range.contains(&index) || !drop(message);
+            index += 1;
+            if !keep {
+                bytes -= message.payload.len();
+            }
+            keep
+        });
+        self.bytes = bytes;
+        (before - self.messages.len()) as u64
+    }
+    // Removes the oldest message that isn't a sequence header, false if
+    // there's none.
+    fn drop_oldest(&mut self) -> bool {
+        let Some(index) = self.messages.iter().position(|m| !is_sequence_header(m)) else {
+            return false;
+        };
+        let message = self.messages.remove(index).unwrap();
+        self.bytes -= message.payload.len();
+        match message.message_type_id {
+            VIDEO => {
+                self.dropped_video += 1;
+                // following frames would reference it
+                self.waiting_for_keyframe |= self.get_last_keyframe().is_none();
+            }
+            AUDIO => self.dropped_audio += 1,
+            _ => {}
+        }
+        true
+    }
+    fn on_dropped(&mut self) {
+        self.dropping = true;
+        self.behind_since.get_or_insert_with(Instant::now);
+    }
+}
+
+fn is_keyframe(message: &MediaMessage) -> bool {
+    // sequence headers have the keyframe frame type too
+    message
+        .get_video_tag()
+        .is_some_and(|tag| tag.is_keyframe() && !tag.is_sequence_header())
+}
+fn is_inter_frame(message: &MediaMessage) -> bool {
+    message
+        .get_video_tag()
+        .is_some_and(|tag| !tag.is_keyframe())
+}
+fn is_sequence_header(message: &MediaMessage) -> bool {
+    match message.message_type_id {
+        AUDIO => is_audio_sequence_header(&message.payload),
+        VIDEO => message
+            .get_video_tag()
+            .is_some_and(|tag| tag.is_sequence_header()),
+        _ => false,
+    }
+}
+fn is_audio_frame(message: &MediaMessage) -> bool {
+    message.message_type_id == AUDIO && !is_audio_sequence_header(&message.payload)
+}
+
+// Messages of a live stream waiting to be sent to one player, the publishing
+// session pushes and the playing session drains. A slow player only loses
+// its own frames, the publisher and other players never wait for it.
+pub struct Subscription {
+    config: SubscriptionConfig,
+    queue: Mutex<Queue>,
+    waker: Option<Waker>,
+}
+impl fmt::Debug for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscription")
+            .field("config", &self.config)
+            .field("queue", &self.queue)
+            .finish_non_exhaustive()
+    }
+}
+impl Subscription {
+    pub fn new(config: SubscriptionConfig, waker: Option<Waker>) -> Self {
+        Self {
+            config,
+            queue: Mutex::new(Queue::default()),
+            waker,
+        }
+    }
+    pub fn push(&self, message: MediaMessage) {
+        let mut queue = self.queue.lock().unwrap();
+        if is_keyframe(&message) {
+            queue.waiting_for_keyframe = false;
+        } else if queue.waiting_for_keyframe && is_inter_frame(&message) {
+            queue.dropped_video += 1;
+            queue.on_dropped();
+            return;
+        }
+        queue.bytes += message.payload.len();
+        queue.messages.push_back(message);
+
+        if queue.is_full(&self.config) {
+            queue.on_dropped();
+            // GOPs followed by a queued keyframe first, nothing after it needs them
+            let last_keyframe = queue.get_last_keyframe().unwrap_or(0);
+            queue.dropped_video += queue.drop_where(0..last_keyframe, is_inter_frame);
+        }
+        if queue.is_full(&self.config) {
+            // then the current GOP, its next frames can't be decoded either
+            let last_keyframe = queue.get_last_keyframe().unwrap_or(0);
+            let dropped = queue.drop_where(last_keyframe..usize::MAX, is_inter_frame);
+            queue.dropped_video += dropped;
+            queue.waiting_for_keyframe |= dropped > 0;
+        }
+        if queue.is_full(&self.config) {
+            queue.dropped_audio += queue.drop_where(0..usize::MAX, is_audio_frame);
+        }
+        while queue.is_full(&self.config) {
+            if !queue.drop_oldest() {
+                queue.overflowed = true;
+                break;
+            }
+        }
+        drop(queue);
+        if let Some(waker) = &self.waker {
+            waker();
+        }
+    }
+    pub fn take(&self) -> VecDeque<MediaMessage> {
+        let mut queue = self.queue.lock().unwrap();
+        if !std::mem::take(&mut queue.dropping) {
+            queue.behind_since = None;
+        }
+        queue.bytes = 0;
+        std::mem::take(&mut queue.messages)
+    }
+    pub fn set_buffer_length(&self, buffer_length: Duration) {
+        self.queue.lock().unwrap().buffer_length = buffer_length;
+    }
+    pub fn get_dropped_video(&self) -> u64 {
+        self.queue.lock().unwrap().dropped_video
+    }
+    pub fn get_dropped_audio(&self) -> u64 {
+        self.queue.lock().unwrap().dropped_audio
+    }
+    // The player kept losing frames for longer than `max_behind` and its
+    // buffer length, or its queue couldn't be kept within bounds at all.
+    pub fn is_behind(&self, now: Instant) -> bool {
+        let queue = self.queue.lock().unwrap();
+        let max_behind = self.config.max_behind + queue.buffer_length;
+        queue.overflowed
+            || queue
+                .behind_since
+                .is_some_and(|since| now.saturating_duration_since(since) > max_behind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    const KEYFRAME: &[u8] = b"\x17\x01\x00\x00\x00k";
+    const INTER_FRAME: &[u8] = b"\x27\x01\x00\x00\x00p";
+    const SEQUENCE_HEADER: &[u8] = b"\x17\x00\x00\x00\x00c";
+    const AUDIO_FRAME: &[u8] = b"\xaf\x01a";
+
+    fn message(message_type_id: u8, timestamp: u32, payload: &'static [u8]) -> MediaMessage {
+        MediaMessage {
+            message_type_id,
+            timestamp,
+            payload: Bytes::from_static(payload),
+        }
+    }
+    fn subscription(max_messages: usize) -> Subscription {
+        let config = SubscriptionConfig {
+            max_messages,
+            ..SubscriptionConfig::default()
+        };
+        Subscription::new(config, None)
+    }
+    fn timestamps(subscription: &Subscription) -> Vec<u32> {
+        subscription.take().iter().map(|m| m.timestamp).collect()
+    }
+
+    #[test]
+    fn overflowing_keyframe_keeps_its_gop() {
+        let subscription = subscription(3);
+        subscription.push(message(VIDEO, 0, KEYFRAME));
+        subscription.push(message(VIDEO, 1, INTER_FRAME));
+        subscription.push(message(VIDEO, 2, INTER_FRAME));
+        subscription.push(message(VIDEO, 3, KEYFRAME));
+        subscription.push(message(VIDEO, 4, INTER_FRAME));
+        assert_eq!(timestamps(&subscription), [0, 3, 4]);
+        assert_eq!(subscription.get_dropped_video(), 2);
+    }
+
+    #[test]
+    fn drops_current_gop_until_next_keyframe() {
+        let subscription = subscription(2);
+        subscription.push(message(VIDEO, 0, KEYFRAME));
+        subscription.push(message(VIDEO, 1, INTER_FRAME));
+        subscription.push(message(VIDEO, 2, INTER_FRAME));
+        subscription.push(message(VIDEO, 3, INTER_FRAME));
+        subscription.push(message(VIDEO, 4, KEYFRAME));
+        assert_eq!(timestamps(&subscription), [0, 4]);
+        assert_eq!(subscription.get_dropped_video(), 3);
+    }
+
+    #[test]
+    fn sequence_header_does_not_end_wait_for_keyframe() {
+        let subscription = subscription(2);
+        subscription.push(message(VIDEO, 0, KEYFRAME));
+        subscription.push(message(VIDEO, 1, INTER_FRAME));
+        subscription.push(message(VIDEO, 2, INTER_FRAME));
+        subscription.take();
+        subscription.push(message(VIDEO, 3, SEQUENCE_HEADER));
+        subscription.push(message(VIDEO, 4, INTER_FRAME));
+        assert_eq!(timestamps(&subscription), [3]);
+    }
+
+    #[test]
+    fn drops_audio_after_inter_frames() {
+        let subscription = subscription(2);
+        subscription.push(message(VIDEO, 0, KEYFRAME));
+        subscription.push(message(AUDIO, 1, AUDIO_FRAME));
+        subscription.push(message(VIDEO, 2, KEYFRAME));
+        assert_eq!(timestamps(&subscription), [0, 2]);
+        assert_eq!(subscription.get_dropped_audio(), 1);
+    }
+
+    #[test]
+    fn keyframe_only_stream_stays_bounded() {
+        let subscription = subscription(4);
+        for timestamp in 0..100 {
+            subscription.push(message(VIDEO, timestamp, KEYFRAME));
+        }
+        assert_eq!(timestamps(&subscription), [96, 97, 98, 99]);
+        assert_eq!(subscription.get_dropped_video(), 96);
+        assert!(!subscription.is_behind(Instant::now()));
+    }
+
+    #[test]
+    fn buffer_length_extends_max_behind() {
+        let subscription = Subscription::new(
+            SubscriptionConfig {
+                max_messages: 1,
+                max_behind: Duration::from_secs(1),
+                ..SubscriptionConfig::default()
+            },
+            None,
+        );
+        subscription.push(message(VIDEO, 0, KEYFRAME));
+        subscription.push(message(VIDEO, 1, KEYFRAME));
+        let later = Instant::now() + Duration::from_secs(3);
+        assert!(subscription.is_behind(later));
+        subscription.set_buffer_length(Duration::from_secs(5));
+        assert!(!subscription.is_behind(later));
+    }
+
+    #[test]
+    fn overflowing_with_sequence_headers_is_behind() {
+        let subscription = subscription(1);
+        subscription.push(message(VIDEO, 0, SEQUENCE_HEADER));
+        subscription.push(message(VIDEO, 1, SEQUENCE_HEADER));
+        assert!(subscription.is_behind(Instant::now()));
+    }
+}
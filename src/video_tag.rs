@@ -0,0 +1,258 @@
+use std::io;
+
+use bytes::Bytes;
+
+// First 4 bits of a video tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    Keyframe,
+    InterFrame,
+    DisposableInterFrame,
+    // made by the server, e.g. when seeking
+    GeneratedKeyframe,
+    // video info or command frame, no picture
+    VideoInfo,
+    Unknown(u8),
+}
+impl FrameType {
+    pub fn from_u8(frame_type: u8) -> Self {
+        match frame_type {
+            1 => FrameType::Keyframe,
+            2 => FrameType::InterFrame,
+            3 => FrameType::DisposableInterFrame,
+            4 => FrameType::GeneratedKeyframe,
+            5 => FrameType::VideoInfo,
+            _ => FrameType::Unknown(frame_type),
+        }
+    }
+}
+
+// Last 4 bits of the first byte of a video tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    SorensonH263,
+    ScreenVideo,
+    Vp6,
+    Vp6Alpha,
+    ScreenVideo2,
+    Avc,
+    // not in the FLV spec, the id most servers and encoders agree on
+    Hevc,
+    Unknown(u8),
+}
+impl VideoCodec {
+    pub fn from_u8(codec_id: u8) -> Self {
+        match codec_id {
+            2 => VideoCodec::SorensonH263,
+            3 => VideoCodec::ScreenVideo,
+            4 => VideoCodec::Vp6,
+            5 => VideoCodec::Vp6Alpha,
+            6 => VideoCodec::ScreenVideo2,
+            7 => VideoCodec::Avc,
+            12 => VideoCodec::Hevc,
+            _ => VideoCodec::Unknown(codec_id),
+        }
+    }
+    // AVC and HEVC tags carry a packet type and a composition time offset.
+    pub fn has_packet_type(&self) -> bool {
+        matches!(self, VideoCodec::Avc | VideoCodec::Hevc)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AvcPacketType {
+    // AVCDecoderConfigurationRecord (HEVCDecoderConfigurationRecord for HEVC)
+    SequenceHeader,
+    Nalu,
+    EndOfSequence,
+    Unknown(u8),
+}
+impl AvcPacketType {
+    pub fn from_u8(packet_type: u8) -> Self {
+        match packet_type {
+            0 => AvcPacketType::SequenceHeader,
+            1 => AvcPacketType::Nalu,
+            2 => AvcPacketType::EndOfSequence,
+            _ => AvcPacketType::Unknown(packet_type),
+        }
+    }
+}
+
+// Header of the legacy FLV video tag carried by Video (9) messages, the
+// payload after it is shared with the message, not copied.
+//
+//     +------------+----------+-----------------+----------------------+------
+//     | frame type | codec id | AVCPacketType   | composition time     | data
+//     |   4 bits   |  4 bits  | 1 byte, AVC/HEVC| SI24 ms, AVC/HEVC    |
+//     +------------+----------+-----------------+----------------------+------
+//
+// See the video tags section of the FLV file format spec.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoTag {
+    frame_type: FrameType,
+    codec: VideoCodec,
+    packet_type: Option<AvcPacketType>,
+    composition_time: i32,
+    payload: Bytes,
+}
+impl VideoTag {
+    pub fn parse(data: &Bytes) -> io::Result<Self> {
+        let invalid = |reason: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid video tag: {}", reason),
+            )
+        };
+        let Some(&first) = data.first() else {
+            return Err(invalid("empty"));
+        };
+        let frame_type = FrameType::from_u8(first >> 4);
+        let codec = VideoCodec::from_u8(first & 0x0F);
+        if !codec.has_packet_type() {
+            return Ok(Self {
+                frame_type,
+                codec,
+                packet_type: None,
+                composition_time: 0,
+                payload: data.slice(1..),
+            });
+        }
+        let [_, packet_type, a, b, c, ..] = data[..] else {
+            return Err(invalid("truncated AVC header"));
+        };
+        // sign extend the 24 bit value
+        let composition_time = i32::from_be_bytes([a, b, c, 0]) >> 8;
+        Ok(Self {
+            frame_type,
+            codec,
+            packet_type: Some(AvcPacketType::from_u8(packet_type)),
+            composition_time,
+            payload: data.slice(5..),
+        })
+    }
+    pub fn get_frame_type(&self) -> FrameType {
+        self.frame_type
+    }
+    pub fn get_codec(&self) -> VideoCodec {
+        self.codec
+    }
+    pub fn get_packet_type(&self) -> Option<AvcPacketType> {
+        self.packet_type
+    }
+    // Milliseconds between decoding and presentation, 0 without B frames.
+    pub fn get_composition_time(&self) -> i32 {
+        self.composition_time
+    }
+    // The codec data after the tag header, length prefixed NAL units for AVC
+    // and HEVC frames.
+    pub fn get_payload(&self) -> &Bytes {
+        &self.payload
+    }
+    pub fn is_keyframe(&self) -> bool {
+        matches!(
+            self.frame_type,
+            FrameType::Keyframe | FrameType::GeneratedKeyframe
+        )
+    }
+    pub fn is_sequence_header(&self) -> bool {
+        self.packet_type == Some(AvcPacketType::SequenceHeader)
+    }
+    pub fn is_end_of_sequence(&self) -> bool {
+        self.packet_type == Some(AvcPacketType::EndOfSequence)
+    }
+    // The message timestamp is the decoding timestamp.
+    pub fn get_dts(&self, timestamp: u32) -> u32 {
+        timestamp
+    }
+    pub fn get_pts(&self, timestamp: u32) -> u32 {
+        timestamp.wrapping_add_signed(self.composition_time)
+    }
+    // NAL units of an AVC/HEVC frame, `length_size` is the size of their length
+    // prefix from the decoder configuration record, 4 in practice. Stops at
+    // the first truncated one.
+    pub fn nal_units(&self, length_size: usize) -> impl Iterator<Item = Bytes> + '_ {
+        let mut offset = 0;
+        std::iter::from_fn(move || {
+            if self.packet_type != Some(AvcPacketType::Nalu) {
+                return None;
+            }
+            let prefix = self.payload.get(offset..offset + length_size)?;
+            let length = prefix
+                .iter()
+                .fold(0usize, |length, &b| (length << 8) | b as usize);
+            let start = offset + length_size;
+            let nal_unit = self.payload.get(start..start + length)?;
+            offset = start + length;
+            Some(self.payload.slice_ref(nal_unit))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &'static [u8]) -> io::Result<VideoTag> {
+        VideoTag::parse(&Bytes::from_static(data))
+    }
+
+    #[test]
+    fn parses_avc_header() {
+        let tag = parse(b"\x27\x01\xFF\xFF\xF6nalu").unwrap();
+        assert_eq!(tag.get_frame_type(), FrameType::InterFrame);
+        assert_eq!(tag.get_codec(), VideoCodec::Avc);
+        assert_eq!(tag.get_packet_type(), Some(AvcPacketType::Nalu));
+        assert_eq!(tag.get_composition_time(), -10);
+        assert_eq!(tag.get_dts(1000), 1000);
+        assert_eq!(tag.get_pts(1000), 990);
+        assert_eq!(&tag.get_payload()[..], b"nalu");
+        assert!(!tag.is_keyframe());
+
+        let tag = parse(b"\x1C\x01\x00\x00\x28").unwrap();
+        assert_eq!(tag.get_codec(), VideoCodec::Hevc);
+        assert_eq!(tag.get_composition_time(), 40);
+        assert_eq!(tag.get_pts(1000), 1040);
+        assert!(tag.is_keyframe());
+    }
+
+    #[test]
+    fn rejects_truncated_header() {
+        assert!(parse(b"").is_err());
+        assert!(parse(b"\x17\x01\x00\x00").is_err());
+    }
+
+    #[test]
+    fn parses_codec_without_packet_type() {
+        let tag = parse(b"\x42data").unwrap();
+        assert_eq!(tag.get_frame_type(), FrameType::GeneratedKeyframe);
+        assert_eq!(tag.get_codec(), VideoCodec::SorensonH263);
+        assert_eq!(tag.get_packet_type(), None);
+        assert_eq!(tag.get_composition_time(), 0);
+        assert_eq!(&tag.get_payload()[..], b"data");
+        assert!(tag.is_keyframe());
+        assert!(!tag.is_sequence_header());
+        assert_eq!(tag.nal_units(4).count(), 0);
+    }
+
+    #[test]
+    fn detects_sequence_header_and_end_of_sequence() {
+        let sequence_header = parse(b"\x17\x00\x00\x00\x00config").unwrap();
+        assert!(sequence_header.is_sequence_header());
+        assert!(!sequence_header.is_end_of_sequence());
+        let end_of_sequence = parse(b"\x17\x02\x00\x00\x00").unwrap();
+        assert!(end_of_sequence.is_end_of_sequence());
+        assert!(!end_of_sequence.is_sequence_header());
+    }
+
+    #[test]
+    fn splits_nal_units() {
+        let tag =
+            parse(b"\x17\x01\x00\x00\x00\x00\x00\x00\x02ab\x00\x00\x00\x01c\x00\x00\x00\x05de")
+                .unwrap();
+        let nal_units: Vec<_> = tag.nal_units(4).collect();
+        assert_eq!(nal_units, [&b"ab"[..], &b"c"[..]]);
+        let tag = parse(b"\x17\x01\x00\x00\x00\x00\x01x\x00\x02yz").unwrap();
+        let nal_units: Vec<_> = tag.nal_units(2).collect();
+        assert_eq!(nal_units, [&b"x"[..], &b"yz"[..]]);
+    }
+}